@@ -1,191 +1,708 @@
 use macroquad::prelude::*;
-use num_complex::{Complex, ComplexFloat};
-use std::array::from_fn;
+use serde::{Deserialize, Serialize};
 use std::{
+    array::from_fn,
     collections::{HashMap, HashSet},
+    ops::Range,
     sync::{LazyLock, RwLock},
     time::{Duration, Instant},
 };
 
 use crate::{
-    BORDER_COLOR, BORDER_THICKNESS, Zoom,
-    body::{Body, BodyID, get_rectangle},
+    BORDER_COLOR, BORDER_THICKNESS, Camera,
+    body::{Body, BodyID, DT, get_rectangle},
+    expansion::LocalExpansion,
+    force_law::{ForceLaw, Sources},
+    orbit::draw_box,
+    vector::{DIMENSIONS, ORTHANTS_N, Vector, VectorExt},
 };
 
 pub type NodeID = usize;
+pub type MortonKey = u64;
 
 pub static THETA: LazyLock<RwLock<f64>> = LazyLock::new(|| RwLock::new(0.0));
 const DELTA_THETA: f64 = 0.1;
-const MAX_THETA: f64 = 3.0;
+pub const MAX_THETA: f64 = 3.0;
+/// Whether to draw the tree.
+pub static DRAW: LazyLock<RwLock<bool>> = LazyLock::new(|| RwLock::new(false));
 
+/// Bits per coordinate in a Morton key.
+const MORTON_BITS: u32 = 63 / DIMENSIONS as u32;
+/// Nodes at this depth are never split, so bodies at identical positions
+/// end up together in a leaf instead of being split forever.
+pub const MAX_DEPTH: u32 = 20;
+/// Leaves hold up to this many bodies, which are then handled directly.
+pub const LEAF_CAPACITY: usize = 4;
+/// A reused tree is rebuilt once more than this fraction of the bodies
+/// has left its leaf.
+const MAX_MOVED_FRACTION: f64 = 0.1;
+/// The relative-acceleration tolerance per unit of theta,
+/// so that the theta controller drives every criterion.
+const RELATIVE_ALPHA_PER_THETA: f64 = 0.01;
+/// The root cube is padded by this fraction of its size on every side,
+/// so that a reused tree still holds the bodies after they have moved a bit.
+const ROOT_MARGIN: f64 = 0.05;
+
+/// A square in 2D, a cube in 3D.
 #[derive(Clone, Debug)]
-pub struct Square {
-    pub top_left: Complex<f64>,
+pub struct Cube {
+    /// The corner with the smallest coordinates.
+    pub corner: Vector,
     pub size: f64,
 }
 
+/// An axis-aligned box, which is a cuboid in 3D.
 #[derive(Clone)]
 pub struct Rectangle {
-    pub top_left: Complex<f64>,
-    pub bottom_right: Complex<f64>,
+    /// The corner with the smallest coordinates.
+    pub top_left: Vector,
+    /// The corner with the largest coordinates.
+    pub bottom_right: Vector,
 }
 
-#[derive(Clone, Debug)]
-pub enum QuadtreeNodeBodies {
-    All,
-    Bodies(HashSet<BodyID>),
+/// Spreads the lower `MORTON_BITS` bits of `n` apart, `DIMENSIONS` bits from each other.
+fn spread_bits(n: u64) -> u64 {
+    (0..MORTON_BITS).fold(0, |spread, bit| {
+        spread | (n >> bit & 1) << (bit as usize * DIMENSIONS)
+    })
+}
+
+impl Cube {
+    /// The position of `pos` along the Z-order curve covering the cube.
+    /// Positions on or past the faces are clamped into the cube.
+    pub fn get_morton_key(&self, pos: Vector) -> MortonKey {
+        let max = (1u64 << MORTON_BITS) - 1;
+        let quantize = |offset: f64| {
+            ((offset / self.size * (1u64 << MORTON_BITS) as f64).max(0.0) as u64).min(max)
+        };
+
+        (pos - self.corner)
+            .components()
+            .iter()
+            .enumerate()
+            .fold(0, |key, (k, offset)| {
+                key | spread_bits(quantize(*offset)) << k
+            })
+    }
+
+    /// Whether `pos` lies within the cube, the far faces excluded.
+    pub fn contains(&self, pos: Vector) -> bool {
+        (pos - self.corner)
+            .components()
+            .iter()
+            .all(|offset| (0.0..self.size).contains(offset))
+    }
 }
 
 #[derive(Clone, Debug)]
-pub struct QuadtreeNode {
-    pub children: Option<[[NodeID; 2]; 2]>,
-    pub bodies: QuadtreeNodeBodies,
-    pub square: Square,
+pub struct TreeNode {
+    /// Child `i` lies in the upper half of the node along the axis `k` if bit `k` of `i` is set.
+    pub children: Option<[NodeID; ORTHANTS_N]>,
+    /// The range of `Tree::bodies` lying in the node.
+    pub bodies: Range<usize>,
+    pub cube: Cube,
+    pub depth: u32,
     pub total_mass: f64,
-    pub pos: Complex<f64>,
+    pub pos: Vector,
+    /// The distance from the centre of mass to the farthest corner of the cube.
+    pub bmax: f64,
+    /// What the node acts on other bodies with under the force law.
+    pub sources: Sources,
 }
 
-impl QuadtreeNode {
-    pub fn draw(id: NodeID, quadtree_nodes: &mut Vec<Self>, zoom: &Zoom) {
-        let current_node = &quadtree_nodes[id];
+impl TreeNode {
+    /// The distance from `pos` to the nearest point of the cube.
+    pub fn get_min_distance(&self, pos: Vector) -> f64 {
+        (pos - self.cube.corner)
+            .components()
+            .iter()
+            .map(|offset| (-offset).max(offset - self.cube.size).max(0.0).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
 
-        let border = BORDER_THICKNESS / zoom.zoom;
+    /// Whether the node can stand in for its bodies when acting on a body at
+    /// `pos` whose acceleration per unit response was `old_acceleration` in the previous step.
+    pub fn accepts(
+        &self,
+        mac: Mac,
+        pos: Vector,
+        theta: f64,
+        old_acceleration: f64,
+        force_law: ForceLaw,
+    ) -> bool {
+        let r = (self.pos - pos).length();
 
-        if let Some(children) = current_node.children {
-            draw_line(
-                current_node.square.top_left.re() as f32,
-                (current_node.square.top_left.im() + current_node.square.size / 2.0) as f32,
-                (current_node.square.top_left.re() + current_node.square.size) as f32,
-                (current_node.square.top_left.im() + current_node.square.size / 2.0) as f32,
-                border,
-                BORDER_COLOR,
-            );
+        match mac {
+            Mac::Geometric => self.cube.size / r <= theta,
+            Mac::MinDistance => self.cube.size / self.get_min_distance(pos) <= theta,
+            Mac::Bmax => self.bmax / r <= theta,
+            Mac::RelativeAcceleration => {
+                let field = self
+                    .sources
+                    .get_monopoles()
+                    .map(|monopole| {
+                        force_law
+                            .get_field(pos, monopole.pos, monopole.source)
+                            .length()
+                    })
+                    .sum::<f64>();
+
+                // Bodies inside the node would see its monopoles at a random offset
+                self.get_min_distance(pos) > 0.0
+                    && field * (self.cube.size / r).powi(2)
+                        <= RELATIVE_ALPHA_PER_THETA * theta * old_acceleration
+            }
+        }
+    }
+
+    /// The criterion for a pair of nodes acting on each other as a whole,
+    /// under `mac` as `Mac::for_pairs` makes it.
+    pub fn accepts_pair(&self, other: &Self, mac: Mac, theta: f64) -> bool {
+        let r = (self.pos - other.pos).length();
+
+        match mac.for_pairs() {
+            Mac::Geometric => (self.cube.size + other.cube.size) / r <= theta,
+            Mac::MinDistance => {
+                let min_distance = (r - self.bmax - other.bmax).max(0.0);
+                (self.cube.size + other.cube.size) / min_distance <= theta
+            }
+            Mac::Bmax | Mac::RelativeAcceleration => (self.bmax + other.bmax) / r <= theta,
+        }
+    }
+}
+
+/// A tree in 2D and an octree in 3D, stored as a flat vector of nodes
+/// over the bodies sorted along the Morton curve, so every node owns
+/// a contiguous range of bodies. Children are always stored after their parent.
+pub struct Tree {
+    pub nodes: Vec<TreeNode>,
+    /// Sorted by the Morton key, at least down to the depth of the leaves.
+    pub bodies: Vec<(MortonKey, BodyID)>,
+    /// A copy of each body in `bodies`, stored alongside for locality.
+    pub points: Vec<Body>,
+}
+
+impl Tree {
+    pub const ROOT_ID: NodeID = 0;
+
+    /// The smallest cube around the bodies, centred on their bounding box.
+    pub fn get_cube(bodies: &HashMap<BodyID, Body>) -> Cube {
+        let rectangle = get_rectangle(bodies);
+        let extents = (rectangle.bottom_right - rectangle.top_left).components();
+        let size = extents.iter().copied().fold(0.0, f64::max);
+
+        Cube {
+            corner: rectangle.top_left
+                - Vector::from_components(extents.map(|extent| (size - extent) / 2.0)),
+            size,
+        }
+    }
+
+    pub fn new(bodies: &HashMap<BodyID, Body>, force_law: ForceLaw) -> Self {
+        let cube = Self::get_cube(bodies);
+        let cube = Cube {
+            corner: cube.corner - Vector::splat(ROOT_MARGIN * cube.size),
+            size: cube.size * (1.0 + 2.0 * ROOT_MARGIN),
+        };
+
+        let mut sorted_bodies = bodies
+            .iter()
+            .map(|(body_id, body)| (cube.get_morton_key(body.pos), *body_id))
+            .collect::<Vec<_>>();
+        sorted_bodies.sort_unstable();
+
+        let points = sorted_bodies
+            .iter()
+            .map(|(_, body_id)| *bodies.get(body_id).unwrap())
+            .collect();
+
+        let mut tree = Self {
+            nodes: vec![TreeNode {
+                children: None,
+                bodies: 0..sorted_bodies.len(),
+                cube,
+                depth: 0,
+                total_mass: 0.0,
+                pos: Vector::ZERO,
+                bmax: 0.0,
+                sources: Sources::default(),
+            }],
+            bodies: sorted_bodies,
+            points,
+        };
+
+        tree.split();
+        tree.set_masses(force_law);
+
+        tree
+    }
+
+    /// Splits the range of bodies of `node` into the ranges of its children.
+    fn get_child_ranges(&self, node: &TreeNode) -> [Range<usize>; ORTHANTS_N] {
+        let shift = DIMENSIONS as u32 * (MORTON_BITS - 1 - node.depth);
+        let mask = ORTHANTS_N as MortonKey - 1;
+        let keys = &self.bodies[node.bodies.clone()];
+
+        let mut start = node.bodies.start;
+        from_fn(|orthant| {
+            let end = node.bodies.start
+                + keys.partition_point(|(key, _)| (key >> shift) & mask <= orthant as MortonKey);
+
+            let range = start..end;
+            start = end;
+
+            range
+        })
+    }
+
+    /// Splits the nodes breadth-first until every leaf holds at most
+    /// `LEAF_CAPACITY` bodies or is `MAX_DEPTH` deep.
+    fn split(&mut self) {
+        let mut id = Self::ROOT_ID;
+
+        while id < self.nodes.len() {
+            let node = &self.nodes[id];
+
+            if node.bodies.len() > LEAF_CAPACITY && node.depth < MAX_DEPTH {
+                let child_size = node.cube.size / 2.0;
+                let child_ranges = self.get_child_ranges(node);
+
+                let children: [TreeNode; ORTHANTS_N] = from_fn(|orthant| TreeNode {
+                    children: None,
+                    bodies: child_ranges[orthant].clone(),
+                    cube: Cube {
+                        corner: node
+                            .cube
+                            .corner
+                            .get_orthant(orthant, Vector::splat(child_size)),
+                        size: child_size,
+                    },
+                    depth: node.depth + 1,
+                    total_mass: 0.0,
+                    pos: Vector::ZERO,
+                    bmax: 0.0,
+                    sources: Sources::default(),
+                });
+
+                let first_child_id = self.nodes.len();
+                self.nodes[id].children = Some(from_fn(|orthant| first_child_id + orthant));
+                self.nodes.extend(children);
+            }
+
+            id += 1;
+        }
+    }
+
+    /// Reuses the tree for the bodies having moved: re-sorts the bodies that
+    /// crossed cell boundaries, redistributes the ranges and refits the
+    /// centres of mass. Returns `false` without refitting if the tree has
+    /// degraded and has to be rebuilt from scratch.
+    pub fn refit(&mut self, bodies: &HashMap<BodyID, Body>, force_law: ForceLaw) -> bool {
+        let cube = self.nodes[Self::ROOT_ID].cube.clone();
+        let get_key = |body: &Body| {
+            cube.contains(body.pos)
+                .then(|| cube.get_morton_key(body.pos))
+        };
+
+        // `None` for the bodies that have been merged away
+        let mut keys = Vec::with_capacity(self.bodies.len());
+        for (_, body_id) in &self.bodies {
+            keys.push(match bodies.get(body_id) {
+                Some(body) => match get_key(body) {
+                    Some(key) => Some(key),
+                    None => return false,
+                },
+                None => None,
+            });
+        }
 
-            draw_line(
-                (current_node.square.top_left.re() + current_node.square.size / 2.0) as f32,
-                current_node.square.top_left.im() as f32,
-                (current_node.square.top_left.re() + current_node.square.size / 2.0) as f32,
-                (current_node.square.top_left.im() + current_node.square.size) as f32,
+        let mut new_bodies = Vec::new();
+        if bodies.len() != keys.iter().flatten().count() {
+            let tree_body_ids = self
+                .bodies
+                .iter()
+                .map(|(_, body_id)| *body_id)
+                .collect::<HashSet<_>>();
+
+            for (body_id, body) in bodies {
+                if !tree_body_ids.contains(body_id) {
+                    match get_key(body) {
+                        Some(key) => new_bodies.push((key, *body_id)),
+                        None => return false,
+                    }
+                }
+            }
+        }
+
+        // Whether each body has left its leaf, or been merged away
+        let mut moved = vec![false; self.bodies.len()];
+        for node in self.nodes.iter().filter(|node| node.children.is_none()) {
+            let shift = DIMENSIONS as u32 * (MORTON_BITS - node.depth);
+            for index in node.bodies.clone() {
+                moved[index] = keys[index].map(|key| key.checked_shr(shift))
+                    != Some(self.bodies[index].0.checked_shr(shift));
+            }
+        }
+
+        let moved_n = new_bodies.len() + moved.iter().filter(|moved| **moved).count();
+        if moved_n as f64 > MAX_MOVED_FRACTION * bodies.len() as f64 {
+            return false;
+        }
+
+        // The bodies still in their leaf stay in the order of the leaves, so only
+        // the moved ones are sorted and then merged in, like the new ones
+        let mut retained_bodies = Vec::with_capacity(self.bodies.len());
+        let mut moved_bodies = new_bodies;
+        for (((_, body_id), key), moved) in self.bodies.iter().zip(keys).zip(moved) {
+            match key {
+                Some(key) if moved => moved_bodies.push((key, *body_id)),
+                Some(key) => retained_bodies.push((key, *body_id)),
+                None => {}
+            }
+        }
+        moved_bodies.sort_unstable();
+
+        self.bodies.clear();
+        let mut retained_bodies = retained_bodies.into_iter().peekable();
+        let mut moved_bodies = moved_bodies.into_iter().peekable();
+        while let Some(next) = match (retained_bodies.peek(), moved_bodies.peek()) {
+            (Some(retained), Some(moved)) if moved < retained => moved_bodies.next(),
+            (Some(_), _) => retained_bodies.next(),
+            (None, _) => moved_bodies.next(),
+        } {
+            self.bodies.push(next);
+        }
+
+        self.nodes[Self::ROOT_ID].bodies = 0..self.bodies.len();
+        for id in 0..self.nodes.len() {
+            let node = &self.nodes[id];
+
+            match node.children {
+                Some(children) => {
+                    let child_ranges = self.get_child_ranges(node);
+                    for (child_id, range) in children.iter().zip(child_ranges) {
+                        self.nodes[*child_id].bodies = range;
+                    }
+                }
+                None => {
+                    if node.bodies.len() > LEAF_CAPACITY && node.depth < MAX_DEPTH {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        self.points.clear();
+        self.points.extend(
+            self.bodies
+                .iter()
+                .map(|(_, body_id)| *bodies.get(body_id).unwrap()),
+        );
+
+        self.set_masses(force_law);
+
+        true
+    }
+
+    /// Sets the masses, the centres of mass and the sources bottom-up.
+    fn set_masses(&mut self, force_law: ForceLaw) {
+        for id in (0..self.nodes.len()).rev() {
+            let mut sources = Sources::default();
+            let (total_mass, pos) = match self.nodes[id].children {
+                Some(children) => {
+                    children
+                        .iter()
+                        .fold((0.0, Vector::ZERO), |(total_mass, pos), child_id| {
+                            let child = &self.nodes[*child_id];
+                            sources.add_sources(&child.sources);
+                            (
+                                total_mass + child.total_mass,
+                                pos + child.total_mass * child.pos,
+                            )
+                        })
+                }
+                None => self.points[self.nodes[id].bodies.clone()].iter().fold(
+                    (0.0, Vector::ZERO),
+                    |(total_mass, pos), body| {
+                        sources.add(body.pos, force_law.get_source(body));
+                        (total_mass + body.mass, pos + body.mass * body.pos)
+                    },
+                ),
+            };
+            sources.finish();
+
+            let node = &mut self.nodes[id];
+            node.total_mass = total_mass;
+            node.sources = sources;
+            node.pos = if total_mass != 0.0 {
+                pos / total_mass
+            } else {
+                Vector::ZERO
+            };
+
+            node.bmax = (0..ORTHANTS_N)
+                .map(|orthant| {
+                    (node
+                        .cube
+                        .corner
+                        .get_orthant(orthant, Vector::splat(node.cube.size))
+                        - node.pos)
+                        .length()
+                })
+                .fold(0.0, f64::max);
+        }
+    }
+
+    pub fn draw(&self, camera: &Camera) {
+        let border = BORDER_THICKNESS * camera.get_scale();
+
+        for node in &self.nodes {
+            draw_box(
+                node.cube.corner,
+                Vector::splat(node.cube.size),
                 border,
                 BORDER_COLOR,
             );
-
-            for child in children.iter().flatten() {
-                Self::draw(*child, quadtree_nodes, zoom);
-            }
         }
     }
 
+    /// Adjusts the speed of the body at `index` in `bodies`, walking the tree
+    /// with an explicit stack.
+    #[allow(clippy::too_many_arguments)]
     pub fn adjust_speed(
-        id: NodeID,
-        body_id: BodyID,
-        bodies: &mut HashMap<BodyID, Body>,
-        quadtree_nodes: &mut Vec<Self>,
+        &self,
+        index: usize,
+        body: &mut Body,
+        mac: Mac,
+        theta: f64,
+        old_acceleration: f64,
+        force_law: ForceLaw,
+        stack: &mut Vec<NodeID>,
     ) {
-        let current_node = &quadtree_nodes[id];
-        let bodies_len = bodies.len();
-        let body = bodies.get_mut(&body_id).unwrap();
+        stack.clear();
+        stack.push(Self::ROOT_ID);
 
-        match match &current_node.bodies {
-            QuadtreeNodeBodies::All => bodies_len,
-            QuadtreeNodeBodies::Bodies(node_bodies) => node_bodies.len(),
-        } {
-            0 => (),
-            1 => {
-                if !match &current_node.bodies {
-                    QuadtreeNodeBodies::All => true,
-                    QuadtreeNodeBodies::Bodies(node_bodies) => node_bodies.contains(&body_id),
-                } {
-                    body.adjust_speed(current_node.pos, current_node.total_mass);
+        while let Some(id) = stack.pop() {
+            let node = &self.nodes[id];
+
+            if node.total_mass == 0.0 {
+                continue;
+            }
+
+            if !node.bodies.contains(&index)
+                && node.accepts(mac, body.pos, theta, old_acceleration, force_law)
+            {
+                for monopole in node.sources.get_monopoles() {
+                    body.adjust_speed(monopole.pos, monopole.source, force_law);
                 }
+                continue;
             }
-            _ => {
-                let r = (current_node.pos - body.pos).abs();
-                if current_node.square.size / r <= *THETA.read().unwrap()
-                    && !match &current_node.bodies {
-                        QuadtreeNodeBodies::All => true,
-                        QuadtreeNodeBodies::Bodies(node_bodies) => node_bodies.contains(&body_id),
-                    }
-                {
-                    body.adjust_speed(current_node.pos, current_node.total_mass);
-                } else {
-                    for child in current_node.children.unwrap().iter().flatten() {
-                        Self::adjust_speed(*child, body_id, bodies, quadtree_nodes);
+
+            match node.children {
+                Some(children) => stack.extend(children),
+                None => {
+                    for (rhs_index, rhs_body) in
+                        node.bodies.clone().zip(&self.points[node.bodies.clone()])
+                    {
+                        if rhs_index != index {
+                            body.adjust_speed(
+                                rhs_body.pos,
+                                force_law.get_source(rhs_body),
+                                force_law,
+                            );
+                        }
                     }
                 }
             }
         }
     }
 
-    pub fn split(id: NodeID, bodies: &HashMap<BodyID, Body>, quadtree_nodes: &mut Vec<Self>) {
-        let current_node = &quadtree_nodes[id];
+    /// Adjusts the speeds of `bodies`, every one walking the tree on its own.
+    /// Under `Mac::RelativeAcceleration`, `accelerations` holds the absolute
+    /// accelerations per unit response of the previous step and gets those of
+    /// this one. A body without one yet, such as on the first step or after it
+    /// was added, walks under `Mac::Bmax` instead, as GADGET does, since the
+    /// criterion would open every node for it.
+    pub fn adjust_speeds(
+        &self,
+        bodies: &mut HashMap<BodyID, Body>,
+        mac: Mac,
+        theta: f64,
+        force_law: ForceLaw,
+        accelerations: &mut HashMap<BodyID, f64>,
+    ) {
+        let mut stack = Vec::with_capacity(4 * MAX_DEPTH as usize);
+        for (index, (_, body_id)) in self.bodies.iter().enumerate() {
+            let body = bodies.get_mut(body_id).unwrap();
+            let speed = body.speed;
 
-        if match &current_node.bodies {
-            QuadtreeNodeBodies::All => bodies.len(),
-            QuadtreeNodeBodies::Bodies(node_bodies) => node_bodies.len(),
-        } <= 1
-        {
-            return;
-        }
+            let (body_mac, old_acceleration) = match accelerations.get(body_id) {
+                Some(&old_acceleration) if old_acceleration > 0.0 => (mac, old_acceleration),
+                _ if mac == Mac::RelativeAcceleration => (Mac::Bmax, 0.0),
+                _ => (mac, 0.0),
+            };
+            self.adjust_speed(
+                index,
+                body,
+                body_mac,
+                theta,
+                old_acceleration,
+                force_law,
+                &mut stack,
+            );
 
-        let child_size = current_node.square.size / 2.0;
-
-        let mut children: [[(NodeID, QuadtreeNode); 2]; 2] = from_fn(|i| {
-            from_fn(|j| {
-                (
-                    quadtree_nodes.len() + j + 2 * i,
-                    Self {
-                        children: None,
-                        bodies: QuadtreeNodeBodies::Bodies(HashSet::new()),
-                        square: Square {
-                            top_left: current_node.square.top_left
-                                + Complex::new(j as f64 * child_size, i as f64 * child_size),
-                            size: current_node.square.size / 2.0,
-                        },
-                        total_mass: 0.0,
-                        pos: Complex::ZERO,
+            if mac == Mac::RelativeAcceleration {
+                // A body without a response feels no error either
+                let response = force_law.get_response(body).abs();
+                accelerations.insert(
+                    *body_id,
+                    if response != 0.0 {
+                        (body.speed - speed).length() / DT.get() / response
+                    } else {
+                        f64::INFINITY
                     },
-                )
-            })
-        });
+                );
+            }
+        }
+    }
 
-        for body_id in match &current_node.bodies {
-            QuadtreeNodeBodies::All => Box::new(bodies.keys()) as Box<dyn Iterator<Item = &BodyID>>,
-            QuadtreeNodeBodies::Bodies(node_bodies) => {
-                Box::new(node_bodies.iter()) as Box<dyn Iterator<Item = &BodyID>>
+    /// The accelerations of the bodies in `bodies` order, found by interacting
+    /// cells with cells rather than bodies with cells: well-separated pairs of
+    /// nodes feed each other's local expansions, which are then passed down to
+    /// the bodies, so nearby bodies share the work.
+    pub fn get_dual_tree_accelerations(
+        &self,
+        mac: Mac,
+        theta: f64,
+        force_law: ForceLaw,
+    ) -> Vec<Vector> {
+        let mut accelerations = vec![Vector::ZERO; self.bodies.len()];
+        let mut expansions = self
+            .nodes
+            .iter()
+            .map(|node| LocalExpansion::new(node.pos))
+            .collect::<Vec<_>>();
+
+        let mut stack = vec![(Self::ROOT_ID, Self::ROOT_ID)];
+        while let Some((lhs_id, rhs_id)) = stack.pop() {
+            let lhs = &self.nodes[lhs_id];
+            let rhs = &self.nodes[rhs_id];
+
+            if lhs.total_mass == 0.0 || rhs.total_mass == 0.0 {
+                continue;
             }
-        } {
-            let body = bodies.get(body_id).unwrap();
 
-            let i = ((body.pos.im() - current_node.square.top_left.im()) / child_size) as usize;
-            let j = ((body.pos.re() - current_node.square.top_left.re()) / child_size) as usize;
+            if lhs_id == rhs_id {
+                match lhs.children {
+                    Some(children) => {
+                        for (i, lhs_child_id) in children.iter().enumerate() {
+                            for rhs_child_id in &children[i..] {
+                                stack.push((*lhs_child_id, *rhs_child_id));
+                            }
+                        }
+                    }
+                    None => {
+                        for lhs_index in lhs.bodies.clone() {
+                            for rhs_index in lhs_index + 1..lhs.bodies.end {
+                                self.interact_bodies(
+                                    lhs_index,
+                                    rhs_index,
+                                    force_law,
+                                    &mut accelerations,
+                                );
+                            }
+                        }
+                    }
+                }
+
+                continue;
+            }
 
-            let (_, child_node) = &mut children[i][j];
+            if lhs.accepts_pair(rhs, mac, theta) {
+                for monopole in rhs.sources.get_monopoles() {
+                    expansions[lhs_id].add(monopole.pos, monopole.source, force_law);
+                }
+                for monopole in lhs.sources.get_monopoles() {
+                    expansions[rhs_id].add(monopole.pos, monopole.source, force_law);
+                }
 
-            if let QuadtreeNodeBodies::Bodies(node_bodies) = &mut child_node.bodies {
-                node_bodies.insert(*body_id);
-            } else {
-                unreachable!()
+                continue;
             }
 
-            child_node.total_mass += body.mass;
-            child_node.pos += body.pos * body.mass;
+            match (lhs.children, rhs.children) {
+                (None, None) => {
+                    for lhs_index in lhs.bodies.clone() {
+                        for rhs_index in rhs.bodies.clone() {
+                            self.interact_bodies(
+                                lhs_index,
+                                rhs_index,
+                                force_law,
+                                &mut accelerations,
+                            );
+                        }
+                    }
+                }
+                // Split the larger node
+                (Some(children), rhs_children)
+                    if rhs_children.is_none() || lhs.cube.size >= rhs.cube.size =>
+                {
+                    for child_id in children.iter() {
+                        stack.push((*child_id, rhs_id));
+                    }
+                }
+                (_, rhs_children) => {
+                    for child_id in rhs_children.unwrap().iter() {
+                        stack.push((lhs_id, *child_id));
+                    }
+                }
+            }
         }
 
-        for (_, child) in children.iter_mut().flatten() {
-            if child.total_mass != 0.0 {
-                child.pos /= child.total_mass;
+        // Children are stored after their parents, so the expansions are
+        // complete by the time they are passed further down
+        for id in 0..self.nodes.len() {
+            let node = &self.nodes[id];
+            if node.total_mass == 0.0 {
+                continue;
             }
 
-            quadtree_nodes.push(child.clone());
+            match node.children {
+                Some(children) => {
+                    for child_id in children.iter() {
+                        if self.nodes[*child_id].total_mass != 0.0 {
+                            let expansion = expansions[id];
+                            expansions[*child_id].merge(&expansion);
+                        }
+                    }
+                }
+                None => {
+                    for index in node.bodies.clone() {
+                        let body = &self.points[index];
+                        accelerations[index] +=
+                            force_law.get_response(body) * expansions[id].evaluate(body.pos);
+                    }
+                }
+            }
         }
 
-        for (child_id, _) in children.iter().flatten() {
-            Self::split(*child_id, bodies, quadtree_nodes);
-        }
+        accelerations
+    }
+
+    fn interact_bodies(
+        &self,
+        lhs_index: usize,
+        rhs_index: usize,
+        force_law: ForceLaw,
+        accelerations: &mut [Vector],
+    ) {
+        let lhs = &self.points[lhs_index];
+        let rhs = &self.points[rhs_index];
 
-        let current_node_mut = &mut quadtree_nodes[id];
-        current_node_mut.children = Some(from_fn(|i| from_fn(|j| children[i][j].0)));
+        accelerations[lhs_index] += force_law.get_response(lhs)
+            * force_law.get_field(lhs.pos, rhs.pos, force_law.get_source(rhs));
+        accelerations[rhs_index] += force_law.get_response(rhs)
+            * force_law.get_field(rhs.pos, lhs.pos, force_law.get_source(lhs));
     }
 }
 
@@ -194,11 +711,81 @@ pub enum ThetaAdjustment {
     Decrease = -1,
 }
 
-pub struct BarnesHut;
+/// The multipole acceptance criterion deciding whether a node is far enough
+/// to be replaced by its centre of mass.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mac {
+    /// `size / r <= theta`, with `r` the distance to the centre of mass.
+    #[default]
+    Geometric,
+    /// `size / d <= theta`, with `d` the distance to the nearest point of the cube.
+    MinDistance,
+    /// Salmon-Warren: `bmax / r <= theta`, with `bmax` the distance from
+    /// the centre of mass to the farthest corner of the cube.
+    Bmax,
+    /// GADGET: the estimated error of the monopole, `|a| * (size / r)^2`
+    /// with `a` its acceleration (`G M / r^2` under the inverse-square law),
+    /// must stay below a fraction of the body's acceleration in the previous step.
+    RelativeAcceleration,
+}
+
+impl Mac {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Geometric => "Geometric",
+            Self::MinDistance => "Min distance",
+            Self::Bmax => "Bmax",
+            Self::RelativeAcceleration => "Relative acceleration",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Geometric => Self::MinDistance,
+            Self::MinDistance => Self::Bmax,
+            Self::Bmax => Self::RelativeAcceleration,
+            Self::RelativeAcceleration => Self::Geometric,
+        }
+    }
+
+    /// The criterion applied between pairs of nodes: there are no per-body
+    /// accelerations to compare against, so `RelativeAcceleration` falls back to `Bmax`.
+    pub fn for_pairs(self) -> Self {
+        match self {
+            Self::RelativeAcceleration => Self::Bmax,
+            _ => self,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Traversal {
+    /// Every body walks the tree on its own.
+    #[default]
+    Bodies,
+    /// Nodes interact with nodes, see `Tree::get_dual_tree_accelerations`.
+    DualTree,
+}
+
+#[derive(Default)]
+pub struct BarnesHut {
+    pub traversal: Traversal,
+    pub mac: Mac,
+    /// The absolute accelerations per unit response of the previous step, for `Mac::RelativeAcceleration`.
+    pub accelerations: HashMap<BodyID, f64>,
+    /// The tree of the previous step, if it is to be reused.
+    pub tree: Option<Tree>,
+    /// Whether to refit the previous tree instead of building a new one every step.
+    pub incremental: bool,
+    /// How many steps had to build the tree from scratch.
+    pub rebuilds_n: usize,
+}
 
 impl BarnesHut {
-    pub const DRAW: bool = false;
     pub const COLOR: Color = RED;
+    pub const DUAL_TREE_COLOR: Color = PINK;
 
     pub fn adjust_theta(adjustment: ThetaAdjustment) {
         let mut write = THETA.write().unwrap();
@@ -206,66 +793,292 @@ impl BarnesHut {
         *write = write.clamp(0.0, MAX_THETA);
     }
 
-    pub fn handle(bodies: &mut HashMap<BodyID, Body>, zoom: &Zoom) -> Duration {
+    pub fn get_tree(&mut self, bodies: &HashMap<BodyID, Body>, force_law: ForceLaw) -> &Tree {
+        let reused = self.incremental
+            && self
+                .tree
+                .as_mut()
+                .is_some_and(|tree| tree.refit(bodies, force_law));
+
+        if !reused {
+            self.rebuilds_n += 1;
+            self.tree = Some(Tree::new(bodies, force_law));
+        }
+
+        self.tree.as_ref().unwrap()
+    }
+
+    pub fn handle(
+        &mut self,
+        bodies: &mut HashMap<BodyID, Body>,
+        force_law: ForceLaw,
+        camera: &Camera,
+    ) -> Duration {
         let start = Instant::now();
 
-        let rectangle = get_rectangle(bodies);
+        let (traversal, mac) = (self.traversal, self.mac);
+        let mut accelerations = std::mem::take(&mut self.accelerations);
+        let tree = self.get_tree(bodies, force_law);
+
+        let theta = *THETA.read().unwrap();
+        match traversal {
+            Traversal::Bodies => {
+                tree.adjust_speeds(bodies, mac, theta, force_law, &mut accelerations);
+            }
+            Traversal::DualTree => {
+                for ((_, body_id), acceleration) in tree
+                    .bodies
+                    .iter()
+                    .zip(tree.get_dual_tree_accelerations(mac, theta, force_law))
+                {
+                    bodies.get_mut(body_id).unwrap().accelerate(acceleration);
+                }
+            }
+        }
 
-        let width = rectangle.bottom_right.re() - rectangle.top_left.re();
-        let height = rectangle.bottom_right.im() - rectangle.top_left.im();
+        let end = start.elapsed();
 
-        let top_left;
-        let size;
+        if *DRAW.read().unwrap() {
+            tree.draw(camera);
+        }
 
-        if width >= height {
-            top_left = Complex::new(
-                rectangle.top_left.re(),
-                rectangle.top_left.im() - (width - height) / 2.0,
-            );
-            size = width;
-        } else {
-            top_left = Complex::new(
-                rectangle.top_left.re() - (height - width) / 2.0,
-                rectangle.top_left.im(),
-            );
-            size = height;
+        // Merged bodies are gone for good
+        accelerations.retain(|body_id, _| bodies.contains_key(body_id));
+        self.accelerations = accelerations;
+
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direct::Direct;
+    use ::rand::{Rng, SeedableRng, rngs::StdRng};
+
+    fn new_body(components: [f64; DIMENSIONS]) -> Body {
+        Body {
+            pos: Vector::from_components(components),
+            speed: Vector::ZERO,
+            mass: 1.0,
+            radius: 0.0,
+            density: 1.0,
+            charge: 1.0,
+            species: 0,
         }
+    }
 
-        let square = Square { top_left, size };
+    fn new_bodies(positions: impl IntoIterator<Item = [f64; DIMENSIONS]>) -> HashMap<BodyID, Body> {
+        positions
+            .into_iter()
+            .enumerate()
+            .map(|(body_id, components)| (body_id as BodyID, new_body(components)))
+            .collect()
+    }
 
-        let mut quadtree_nodes: Vec<QuadtreeNode> = vec![QuadtreeNode {
-            children: None,
-            bodies: QuadtreeNodeBodies::All,
-            square,
-            total_mass: 0.0,
-            pos: Complex::ZERO,
-        }];
-        let root_id = 0;
+    /// Checks that every body is in exactly one leaf, that the leaves are split
+    /// as far as they can be and no further than `MAX_DEPTH`.
+    fn check_tree(tree: &Tree, bodies_n: usize) {
+        let mut counts = vec![0; bodies_n];
+        for node in &tree.nodes {
+            assert!(node.depth <= MAX_DEPTH);
+            match node.children {
+                Some(children) => {
+                    let len = children
+                        .iter()
+                        .map(|child_id| tree.nodes[*child_id].bodies.len())
+                        .sum::<usize>();
+                    assert_eq!(len, node.bodies.len());
+                }
+                None => {
+                    assert!(node.bodies.len() <= LEAF_CAPACITY || node.depth == MAX_DEPTH);
+                    for index in node.bodies.clone() {
+                        counts[index] += 1;
+                    }
+                }
+            }
+        }
+        assert!(counts.iter().all(|count| *count == 1));
+        assert!(tree.nodes.iter().all(|node| node.pos.is_finite()));
+    }
 
-        QuadtreeNode::split(root_id, bodies, &mut quadtree_nodes);
+    /// Runs both traversals, from scratch and refitted, and checks that the
+    /// tree holds together and that the speeds stay finite.
+    fn check_solver(bodies: HashMap<BodyID, Body>) {
+        let bodies_n = bodies.len();
+        check_tree(&Tree::new(&bodies, ForceLaw::Newtonian), bodies_n);
 
-        for body_id in bodies.keys().cloned().collect::<HashSet<_>>() {
-            QuadtreeNode::adjust_speed(root_id, body_id, bodies, &mut quadtree_nodes);
+        for traversal in [Traversal::Bodies, Traversal::DualTree] {
+            for mac in [Mac::Geometric, Mac::RelativeAcceleration] {
+                let mut bodies = bodies.clone();
+                let mut barnes_hut = BarnesHut {
+                    traversal,
+                    mac,
+                    incremental: true,
+                    ..Default::default()
+                };
+                for _ in 0..2 {
+                    barnes_hut.handle(&mut bodies, ForceLaw::Newtonian, &Camera::default());
+                    check_tree(barnes_hut.tree.as_ref().unwrap(), bodies_n);
+                    assert!(bodies.values().all(|body| body.speed.is_finite()));
+                }
+            }
         }
+    }
 
-        let end = start.elapsed();
+    #[test]
+    fn coincident_bodies() {
+        let bodies = new_bodies(std::iter::repeat_n([1.0; DIMENSIONS], 100));
+        check_solver(bodies.clone());
 
-        if Self::DRAW {
-            let root = &quadtree_nodes[root_id];
-            let border = BORDER_THICKNESS / zoom.zoom;
+        // They pull in no particular direction, so not at all
+        let mut bodies = bodies;
+        BarnesHut::default().handle(&mut bodies, ForceLaw::Newtonian, &Camera::default());
+        assert!(bodies.values().all(|body| body.speed == Vector::ZERO));
 
-            draw_rectangle_lines(
-                root.square.top_left.re() as f32,
-                root.square.top_left.im() as f32,
-                root.square.size as f32,
-                root.square.size as f32,
-                border,
-                BORDER_COLOR,
+        // Coincident bodies next to a distinct one
+        check_solver(new_bodies(
+            std::iter::repeat_n([1.0; DIMENSIONS], 50).chain([[2.0; DIMENSIONS]]),
+        ));
+    }
+
+    #[test]
+    fn bodies_on_edges() {
+        // A lattice puts bodies on the faces of the root and of the cells within
+        let side = 8usize;
+        let positions = (0..side.pow(DIMENSIONS as u32))
+            .map(|index| from_fn(|k| (index / side.pow(k as u32) % side) as f64 * 0.5));
+        check_solver(new_bodies(positions));
+
+        // The same along a single line, the root being flat
+        check_solver(new_bodies(
+            (0..side).map(|index| from_fn(|k| if k == 0 { index as f64 } else { 0.0 })),
+        ));
+    }
+
+    /// A small cluster of bodies at rest, spread uniformly over a square or a cube.
+    fn get_cluster(bodies_n: usize) -> HashMap<BodyID, Body> {
+        let mut rng = StdRng::seed_from_u64(0);
+        new_bodies((0..bodies_n).map(|_| from_fn(|_| rng.random_range(0.0..100.0))))
+    }
+
+    /// The accelerations of `bodies` under `traversal` and `mac`, the bodies walking
+    /// the tree with the accelerations of the previous step in `old_accelerations`.
+    fn get_accelerations(
+        bodies: &HashMap<BodyID, Body>,
+        traversal: Traversal,
+        mac: Mac,
+        theta: f64,
+        old_accelerations: &mut HashMap<BodyID, f64>,
+    ) -> HashMap<BodyID, Vector> {
+        let tree = Tree::new(bodies, ForceLaw::Newtonian);
+
+        match traversal {
+            Traversal::Bodies => {
+                let mut stepped = bodies.clone();
+                tree.adjust_speeds(
+                    &mut stepped,
+                    mac,
+                    theta,
+                    ForceLaw::Newtonian,
+                    old_accelerations,
+                );
+
+                stepped
+                    .iter()
+                    .map(|(body_id, body)| {
+                        (*body_id, (body.speed - bodies[body_id].speed) / DT.get())
+                    })
+                    .collect()
+            }
+            Traversal::DualTree => tree
+                .bodies
+                .iter()
+                .map(|(_, body_id)| *body_id)
+                .zip(tree.get_dual_tree_accelerations(mac, theta, ForceLaw::Newtonian))
+                .collect(),
+        }
+    }
+
+    /// The mean error of `accelerations` relative to those of `Direct`.
+    fn get_error(bodies: &HashMap<BodyID, Body>, accelerations: &HashMap<BodyID, Vector>) -> f64 {
+        let mut direct = bodies.clone();
+        Direct::handle(&mut direct, ForceLaw::Newtonian);
+
+        accelerations
+            .iter()
+            .map(|(body_id, acceleration)| {
+                let expected = (direct[body_id].speed - bodies[body_id].speed) / DT.get();
+                (*acceleration - expected).length() / expected.length()
+            })
+            .sum::<f64>()
+            / accelerations.len() as f64
+    }
+
+    #[test]
+    fn traversals_against_direct() {
+        let bodies = get_cluster(500);
+
+        for traversal in [Traversal::Bodies, Traversal::DualTree] {
+            let error = get_error(
+                &bodies,
+                &get_accelerations(&bodies, traversal, Mac::Geometric, 0.5, &mut HashMap::new()),
             );
+            assert!(error < 0.02, "{} with {:?}", error, traversal);
+        }
+    }
 
-            QuadtreeNode::draw(root_id, &mut quadtree_nodes, zoom);
+    #[test]
+    fn macs_against_direct() {
+        let bodies = get_cluster(500);
+
+        for mac in [Mac::Geometric, Mac::MinDistance, Mac::Bmax] {
+            let error = get_error(
+                &bodies,
+                &get_accelerations(&bodies, Traversal::Bodies, mac, 0.5, &mut HashMap::new()),
+            );
+            assert!(error < 0.03, "{} with {:?}", error, mac);
         }
+    }
 
-        end
+    /// Without accelerations from a previous step, the criterion would open every
+    /// node, so the first step walks under `Mac::Bmax`, and the next ones under
+    /// the criterion itself.
+    #[test]
+    fn relative_acceleration_against_direct() {
+        let bodies = get_cluster(500);
+        let mut old_accelerations = HashMap::new();
+
+        for step in 0..2 {
+            let error = get_error(
+                &bodies,
+                &get_accelerations(
+                    &bodies,
+                    Traversal::Bodies,
+                    Mac::RelativeAcceleration,
+                    0.5,
+                    &mut old_accelerations,
+                ),
+            );
+
+            assert_eq!(old_accelerations.len(), bodies.len());
+            // Opening every node would give the direct sum to rounding
+            assert!(
+                error > 1e-9,
+                "{} on step {}, every node opened",
+                error,
+                step
+            );
+            assert!(error < 0.03, "{} on step {}", error, step);
+        }
+    }
+
+    #[test]
+    fn single_body() {
+        let bodies = new_bodies([[3.0; DIMENSIONS]]);
+        check_solver(bodies.clone());
+
+        let tree = Tree::new(&bodies, ForceLaw::Newtonian);
+        assert_eq!(tree.nodes.len(), 1);
     }
 }
@@ -1,60 +1,161 @@
-use crate::{DT, barnes_hut::Rectangle};
-use macroquad::prelude::*;
-use num_complex::{Complex, ComplexFloat};
-use std::{collections::HashMap, num::NonZero, time::Instant};
+use crate::{
+    barnes_hut::Rectangle,
+    drag::SpeciesID,
+    force_law::{FORCE_LAW, ForceLaw},
+    vector::{DIMENSIONS, Vector, VectorExt},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        LazyLock, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// A constant that a scenario may set before the simulation starts. An atomic
+/// rather than a lock like the tunables, as it is read in every interaction.
+pub struct RuntimeConstant(AtomicU64);
+
+impl RuntimeConstant {
+    pub const fn new(value: f64) -> Self {
+        Self(AtomicU64::new(value.to_bits()))
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
 
-pub const G: f64 = 0.05;
+pub const DEFAULT_G: f64 = 0.05;
+pub static G: RuntimeConstant = RuntimeConstant::new(DEFAULT_G);
+pub static DT: RuntimeConstant = RuntimeConstant::new(1.0);
 pub const INITIAL_MASS: f64 = 1.0;
+pub const INITIAL_DENSITY: f64 = 1.0;
 pub const INITIAL_ABS_SPEED: f64 = 0.05;
+/// Bodies start with this charge, of a random sign.
+pub const INITIAL_CHARGE: f64 = 1.0;
 
-pub const BODIES_N: NonZero<usize> = NonZero::new(
-    //500 // Recommended for watching the deterministic chaos
-    1500,
-)
-.unwrap();
+/// Unique, and growing in the order the bodies were created in.
+pub type BodyID = u64;
+
+static NEXT_BODY_ID: AtomicU64 = AtomicU64::new(0);
+
+pub fn new_body_id() -> BodyID {
+    NEXT_BODY_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Makes sure that new IDs don't clash with `body_id`, which came from elsewhere.
+/// Fails for the largest ID, which would leave none to be made after it.
+pub fn reserve_body_id(body_id: BodyID) -> bool {
+    match body_id.checked_add(1) {
+        Some(next_body_id) => {
+            NEXT_BODY_ID.fetch_max(next_body_id, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
 
-pub type BodyID = Instant;
+pub static COLLISIONS: LazyLock<RwLock<Collisions>> =
+    LazyLock::new(|| RwLock::new(Collisions::Merge));
+
+/// What happens to bodies that touch.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Collisions {
+    /// They merge into one, conserving the mass, momentum, volume and charge.
+    #[default]
+    Merge,
+    /// They pass through each other.
+    None,
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Body {
-    pub pos: Complex<f64>,
-    pub speed: Complex<f64>,
+    pub pos: Vector,
+    pub speed: Vector,
     pub mass: f64,
     pub radius: f64,
+    /// The density of the material, which sets the radius.
+    pub density: f64,
+    /// Only felt under force laws with `Coupling::Charge`.
+    pub charge: f64,
+    /// An index into `SPECIES`.
+    pub species: SpeciesID,
 }
 
-pub fn get_rectangle(bodies: &mut HashMap<BodyID, Body>) -> Rectangle {
-    let mut topmost = f64::INFINITY;
-    let mut bottommost = f64::NEG_INFINITY;
-
-    let mut leftmost = f64::INFINITY;
-    let mut rightmost = f64::NEG_INFINITY;
+pub fn get_rectangle(bodies: &HashMap<BodyID, Body>) -> Rectangle {
+    let mut min = [f64::INFINITY; DIMENSIONS];
+    let mut max = [f64::NEG_INFINITY; DIMENSIONS];
 
     for body in bodies.values() {
-        topmost = topmost.min(body.pos.im());
-        bottommost = bottommost.max(body.pos.im() + body.radius);
-
-        leftmost = leftmost.min(body.pos.re());
-        rightmost = rightmost.max(body.pos.re() + body.radius);
+        for (k, component) in body.pos.components().into_iter().enumerate() {
+            min[k] = min[k].min(component);
+            max[k] = max[k].max(component + body.radius);
+        }
     }
 
     Rectangle {
-        top_left: Complex::new(leftmost, topmost),
-        bottom_right: Complex::new(rightmost, bottommost),
+        top_left: Vector::from_components(min),
+        bottom_right: Vector::from_components(max),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Energy {
+    pub kinetic: f64,
+    pub potential: f64,
+}
+
+impl Energy {
+    pub fn get_total(&self) -> f64 {
+        self.kinetic + self.potential
     }
 }
 
 impl Body {
-    pub fn get_radius(mass: f64) -> f64 {
-        mass.powf(1.0 / 3.0)
+    pub fn get_radius(mass: f64, density: f64) -> f64 {
+        (mass / density).powf(1.0 / 3.0)
+    }
+
+    /// Sums the potential over every pair directly, whatever the solver,
+    /// under the current force law.
+    pub fn get_energy(bodies: &HashMap<BodyID, Body>) -> Energy {
+        let force_law = *FORCE_LAW.read().unwrap();
+        let bodies = bodies.values().collect::<Vec<_>>();
+
+        let kinetic = bodies
+            .iter()
+            .map(|body| body.mass * body.speed.length().powi(2) / 2.0)
+            .sum();
+
+        let mut potential = 0.0;
+        for (index, lhs) in bodies.iter().enumerate() {
+            for rhs in &bodies[index + 1..] {
+                let distance = (lhs.pos - rhs.pos).length();
+                if distance != 0.0 {
+                    potential += force_law.get_source(lhs)
+                        * force_law.get_source(rhs)
+                        * force_law.get_potential(distance);
+                }
+            }
+        }
+
+        Energy { kinetic, potential }
     }
 
     pub fn adjust_momentum(bodies: &mut HashMap<BodyID, Body>) {
         let total_momentum = bodies
             .values()
             .map(|body| body.mass * body.speed)
-            .sum::<Complex<f64>>();
-        let delta = -total_momentum / (BODIES_N.get() as f64 * INITIAL_MASS);
+            .sum::<Vector>();
+        let total_mass = bodies.values().map(|body| body.mass).sum::<f64>();
+        let delta = -total_momentum / total_mass;
         for body in bodies.values_mut() {
             body.speed += delta;
         }
@@ -71,7 +172,7 @@ impl Body {
                 let body = bodies.get(body_id).unwrap();
                 body.mass * body.pos
             })
-            .sum::<Complex<f64>>()
+            .sum::<Vector>()
             / mass;
         let speed = pair
             .iter()
@@ -79,19 +180,40 @@ impl Body {
                 let body = bodies.get(body_id).unwrap();
                 body.mass * body.speed
             })
-            .sum::<Complex<f64>>()
+            .sum::<Vector>()
             / mass;
+        let volume = pair
+            .iter()
+            .map(|body_id| {
+                let body = bodies.get(body_id).unwrap();
+                body.mass / body.density
+            })
+            .sum::<f64>();
+        let charge = pair
+            .iter()
+            .map(|body_id| bodies.get(body_id).unwrap().charge)
+            .sum::<f64>();
+        // The merged body is of the species of the heavier one
+        let species = pair
+            .iter()
+            .map(|body_id| bodies.get(body_id).unwrap())
+            .max_by(|lhs, rhs| lhs.mass.total_cmp(&rhs.mass))
+            .unwrap()
+            .species;
 
         bodies.remove(&pair[0]);
         bodies.remove(&pair[1]);
 
         bodies.insert(
-            BodyID::now(),
+            new_body_id(),
             Body {
                 pos,
                 speed,
                 mass,
-                radius: Self::get_radius(mass),
+                radius: Self::get_radius(mass, mass / volume),
+                density: mass / volume,
+                charge,
+                species,
             },
         );
     }
@@ -108,7 +230,7 @@ impl Body {
                     }
 
                     let depth =
-                        lhs_body.radius + rhs_body.radius - (lhs_body.pos - rhs_body.pos).abs();
+                        lhs_body.radius + rhs_body.radius - (lhs_body.pos - rhs_body.pos).length();
 
                     if depth >= 0.0 && depth > deepest_connection_depth {
                         deepest_connection_depth = depth;
@@ -140,15 +262,15 @@ impl Body {
                 }
 
                 let dspeed = lhs_body.speed - rhs_body.speed;
-                let a = dspeed.abs().powi(2);
+                let a = dspeed.length().powi(2);
 
                 if a != 0.0 {
                     let dpos = lhs_body.pos - rhs_body.pos;
 
                     let r = lhs_body.radius + rhs_body.radius;
 
-                    let b = (dpos.re() * dspeed.re() + dpos.im() * dspeed.im()) * 2.0;
-                    let c = dpos.abs().powi(2) - r.powi(2);
+                    let b = dpos.dot(dspeed) * 2.0;
+                    let c = dpos.length().powi(2) - r.powi(2);
                     let d = b.powi(2) - 4.0 * a * c;
 
                     let d_sqrt = d.sqrt();
@@ -169,13 +291,17 @@ impl Body {
         earliest_collision_pair.map(|pair| (earliest_collision_time, pair))
     }
 
+    pub fn drift(lambda: f64, bodies: &mut HashMap<BodyID, Body>) {
+        for body in bodies.values_mut() {
+            body.pos += body.speed * lambda;
+        }
+    }
+
     pub fn update_bodies(lambda: f64, bodies: &mut HashMap<BodyID, Body>) {
         let collision = Self::get_earliest_collision(lambda, bodies);
         match collision {
             Some((time, pair)) => {
-                for body in bodies.values_mut() {
-                    body.pos += body.speed * time;
-                }
+                Self::drift(time, bodies);
 
                 Self::connect(pair, bodies);
                 Self::connect_all(bodies);
@@ -184,16 +310,16 @@ impl Body {
                     Self::update_bodies(lambda - time, bodies)
                 }
             }
-            None => {
-                for body in bodies.values_mut() {
-                    body.pos += body.speed * lambda;
-                }
-            }
+            None => Self::drift(lambda, bodies),
         }
     }
 
-    pub fn adjust_speed(&mut self, pos: Complex<f64>, mass: f64) {
-        let r = pos - self.pos;
-        self.speed += DT * G * mass * r / r.abs().powi(3);
+    /// Accelerates the body by the field of a `source` at `pos`.
+    pub fn adjust_speed(&mut self, pos: Vector, source: f64, force_law: ForceLaw) {
+        self.accelerate(force_law.get_response(self) * force_law.get_field(self.pos, pos, source));
+    }
+
+    pub fn accelerate(&mut self, acceleration: Vector) {
+        self.speed += DT.get() * acceleration;
     }
 }
@@ -0,0 +1,174 @@
+use crate::{
+    WORLD_SIZE,
+    body::{Body, BodyID},
+    orbit::ORBIT,
+    vector::Vector,
+};
+use macroquad::prelude::*;
+use std::{collections::HashMap, ops::Range};
+
+pub const ZOOM_STEP: f32 = 1.2;
+pub const ZOOM_RANGE: Range<f32> = 0.3..5.0;
+/// How much wider than the bodies the view is when following all of them.
+const FIT_MARGIN: f32 = 1.1;
+
+/// What the camera keeps in the middle of the screen.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Follow {
+    /// Staying where it was panned to.
+    #[default]
+    Nothing,
+    CenterOfMass,
+    Body(BodyID),
+    /// Every body, zooming to fit them.
+    Everything,
+}
+
+/// The view of the screen plane, into which `ORBIT` projects the world.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    /// The point in the middle of the screen.
+    pub target: Vec2,
+    pub zoom: f32,
+    pub follow: Follow,
+}
+
+impl Follow {
+    pub fn name(&self) -> String {
+        match self {
+            Self::Nothing => "Nothing".to_owned(),
+            Self::CenterOfMass => "Centre of mass".to_owned(),
+            Self::Body(body_id) => format!("Body {}", body_id),
+            Self::Everything => "Everything".to_owned(),
+        }
+    }
+
+    /// The next of the modes picked without a body.
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Nothing | Self::Body(_) => Self::CenterOfMass,
+            Self::CenterOfMass => Self::Everything,
+            Self::Everything => Self::Nothing,
+        }
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            target: Vec2::ZERO,
+            zoom: 1.0,
+            follow: Follow::Nothing,
+        }
+    }
+}
+
+impl Camera {
+    /// How much of the screen plane a pixel spans, the world just fitting the screen
+    /// at a zoom of 1.
+    pub fn get_scale(&self) -> f32 {
+        (WORLD_SIZE[0] as f32 / screen_width()).max(WORLD_SIZE[1] as f32 / screen_height())
+            / self.zoom
+    }
+
+    /// The part of the screen plane that is visible.
+    pub fn get_rect(&self) -> Rect {
+        let size = vec2(screen_width(), screen_height()) * self.get_scale();
+
+        Rect::new(
+            self.target.x - size.x / 2.0,
+            self.target.y - size.y / 2.0,
+            size.x,
+            size.y,
+        )
+    }
+
+    /// The point of the screen plane under the pixel at `pos`.
+    pub fn get_point(&self, pos: Vec2) -> Vec2 {
+        self.get_rect().point() + pos * self.get_scale()
+    }
+
+    /// Moves the view along with a drag of `delta` pixels, following nothing then.
+    pub fn pan(&mut self, delta: Vec2) {
+        self.target -= delta * self.get_scale();
+        self.follow = Follow::Nothing;
+    }
+
+    /// The body that looks closest to the pixel at `pos`, if within `max_distance` pixels
+    /// of its edge.
+    pub fn pick(
+        &self,
+        bodies: &HashMap<BodyID, Body>,
+        pos: Vec2,
+        max_distance: f32,
+    ) -> Option<BodyID> {
+        let orbit = *ORBIT.read().unwrap();
+        let point = self.get_point(pos);
+        let scale = self.get_scale();
+
+        bodies
+            .iter()
+            .map(|(&body_id, body)| {
+                let distance = orbit.project(body.pos).distance(point) - body.radius as f32;
+                (body_id, distance / scale)
+            })
+            .filter(|&(_, distance)| distance <= max_distance)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(body_id, _)| body_id)
+    }
+
+    /// Keeps up with `bodies` as `follow` says, zooming as far as it takes to fit
+    /// them, as the range of the scenario only bounds zooming by hand.
+    /// A body that is gone, having merged, leaves the camera where it was.
+    pub fn update(&mut self, bodies: &HashMap<BodyID, Body>) {
+        if bodies.is_empty() {
+            return;
+        }
+        let orbit = *ORBIT.read().unwrap();
+
+        match self.follow {
+            Follow::Nothing => {}
+            Follow::CenterOfMass => {
+                let total_mass = bodies.values().map(|body| body.mass).sum::<f64>();
+                let center = bodies
+                    .values()
+                    .map(|body| body.mass * body.pos)
+                    .sum::<Vector>()
+                    / total_mass;
+
+                self.target = orbit.project(center);
+            }
+            Follow::Body(body_id) => match bodies.get(&body_id) {
+                Some(body) => self.target = orbit.project(body.pos),
+                None => self.follow = Follow::Nothing,
+            },
+            Follow::Everything => {
+                let mut min = Vec2::INFINITY;
+                let mut max = Vec2::NEG_INFINITY;
+                for body in bodies.values() {
+                    let pos = orbit.project(body.pos);
+                    let radius = body.radius as f32;
+                    min = min.min(pos - radius);
+                    max = max.max(pos + radius);
+                }
+
+                self.target = (min + max) / 2.0;
+                let size = (max - min) * FIT_MARGIN;
+                let fitted = Self { zoom: 1.0, ..*self };
+                let scale = (size.x / screen_width()).max(size.y / screen_height());
+                self.zoom = fitted.get_scale() / scale;
+            }
+        }
+    }
+
+    /// Draws in the screen plane from now on.
+    pub fn set(&self) {
+        let rect = self.get_rect();
+
+        set_camera(&Camera2D {
+            target: self.target,
+            zoom: vec2(2.0 / rect.w, 2.0 / rect.h),
+            ..Default::default()
+        });
+    }
+}
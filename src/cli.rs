@@ -0,0 +1,216 @@
+use crate::{
+    barnes_hut::{Mac, Traversal},
+    import::{Import, ImportError},
+    initial_conditions::CrowdedError,
+    output::{TrajectoryFormat, TrajectoryOutput},
+    scenario::{Scenario, ScenarioError, SolverConfig},
+    snapshot::SnapshotError,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{
+    fmt, io,
+    num::NonZero,
+    path::{self, PathBuf},
+};
+
+/// Gravity simulations side by side, one per solver.
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Without a subcommand, the arguments of `view`.
+    #[command(flatten)]
+    pub view: ViewArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Watch the simulations in a window.
+    View(ViewArgs),
+    /// Step the simulations without a window, reporting the energy drift.
+    Run(RunArgs),
+    /// Time every solver on its own without a window.
+    Bench(BenchArgs),
+    /// Write the scenario out with the overrides applied and every default filled in.
+    Convert(ConvertArgs),
+}
+
+/// What every subcommand starts from.
+#[derive(Args)]
+pub struct Setup {
+    /// The scenario file, the defaults if left out.
+    pub scenario: Option<PathBuf>,
+    /// The seed of the initial conditions, instead of the scenario's.
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// The number of bodies, instead of the scenario's.
+    #[arg(long)]
+    pub bodies: Option<NonZero<usize>>,
+    /// A CSV or JSON file of bodies to start from, instead of the scenario's generator.
+    #[arg(long, conflicts_with = "bodies")]
+    pub import: Option<PathBuf>,
+    /// The solvers to run side by side, instead of the scenario's.
+    #[arg(long, value_delimiter = ',')]
+    pub solvers: Option<Vec<SolverName>>,
+    /// Also stream the bodies to this file: CSV for `.csv`, the compact binary format
+    /// for `.bin` and otherwise a directory of NumPy columns. Can be repeated.
+    #[arg(long)]
+    pub trajectory: Vec<PathBuf>,
+    /// Record the bodies to the `--trajectory` outputs every this many steps.
+    #[arg(long, default_value_t = NonZero::new(10).unwrap())]
+    pub trajectory_interval: NonZero<usize>,
+}
+
+#[derive(Args)]
+pub struct ViewArgs {
+    #[command(flatten)]
+    pub setup: Setup,
+    /// Open a window instead of going fullscreen.
+    #[arg(long)]
+    pub windowed: bool,
+    /// Where F5 saves a snapshot to and F9 loads it from.
+    #[arg(long, default_value = "gravity.snapshot")]
+    pub snapshot: PathBuf,
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub setup: Setup,
+    /// The number of steps to take.
+    #[arg(long, default_value_t = 1000)]
+    pub steps: usize,
+    /// A CSV file to write the duration and, with energy diagnostics, the energy
+    /// of every simulation after every step to, in the scenario's units.
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+    /// A snapshot to carry on from, instead of the initial conditions of the scenario.
+    /// The scenario still sets the outputs, but the snapshot sets the constants, the
+    /// models of the forces and the units.
+    #[arg(long)]
+    pub resume: Option<PathBuf>,
+    /// Where to save a snapshot to after the last step.
+    #[arg(long)]
+    pub snapshot: Option<PathBuf>,
+    /// Also save the snapshot every this many steps, to restart from if the run is cut short.
+    #[arg(long, requires = "snapshot")]
+    pub checkpoint_interval: Option<NonZero<usize>>,
+}
+
+#[derive(Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    pub setup: Setup,
+    /// The number of steps to time every solver over.
+    #[arg(long, default_value_t = 100)]
+    pub steps: usize,
+}
+
+#[derive(Args)]
+pub struct ConvertArgs {
+    #[command(flatten)]
+    pub setup: Setup,
+    /// The file to write the scenario to, the standard output if left out.
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+/// The solvers by the names they are picked by on the command line, with the
+/// Barnes-Hut options left to their defaults.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SolverName {
+    Direct,
+    BarnesHut,
+    DualTree,
+    Multigrid,
+    Grid,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    /// The scenario file, if the scenario came from one, and what is wrong with it.
+    Scenario(Option<PathBuf>, ScenarioError),
+    Io(PathBuf, io::Error),
+    Serialize(toml::ser::Error),
+    Snapshot(PathBuf, SnapshotError),
+    Import(PathBuf, ImportError),
+    Generation(CrowdedError),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Scenario(Some(path), error) => write!(f, "{}: {}", path.display(), error),
+            Self::Scenario(None, error) => write!(f, "{}", error),
+            Self::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            Self::Serialize(error) => write!(f, "cannot write the scenario: {}", error),
+            Self::Snapshot(path, error) => write!(f, "{}: {}", path.display(), error),
+            Self::Import(path, error) => write!(f, "{}: {}", path.display(), error),
+            Self::Generation(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl SolverName {
+    pub fn to_config(self) -> SolverConfig {
+        match self {
+            Self::Direct => SolverConfig::Direct {},
+            Self::BarnesHut => SolverConfig::BarnesHut {
+                traversal: Traversal::Bodies,
+                mac: Mac::default(),
+                incremental: false,
+            },
+            Self::DualTree => SolverConfig::BarnesHut {
+                traversal: Traversal::DualTree,
+                mac: Mac::default(),
+                incremental: false,
+            },
+            Self::Multigrid => SolverConfig::MultiGrid {},
+            Self::Grid => SolverConfig::Grid {},
+        }
+    }
+}
+
+impl Setup {
+    /// The scenario with the command line overriding it.
+    pub fn load(&self) -> Result<Scenario, CliError> {
+        let error = |error| CliError::Scenario(self.scenario.clone(), error);
+
+        let mut scenario = match &self.scenario {
+            Some(path) => Scenario::load(path).map_err(error)?,
+            None => Scenario::default(),
+        };
+
+        if let Some(seed) = self.seed {
+            scenario.initial_conditions.seed = Some(seed);
+        }
+        if let Some(bodies_n) = self.bodies {
+            scenario.initial_conditions.bodies_n = bodies_n;
+        }
+        if let Some(path) = &self.import {
+            // Relative to where it is run from rather than to the scenario
+            let absolute_path =
+                path::absolute(path).map_err(|error| CliError::Io(path.clone(), error))?;
+            let generation = &mut scenario.initial_conditions;
+            generation.generator = None;
+            generation.import = Some(Import::new(absolute_path));
+        }
+        if let Some(solvers) = &self.solvers {
+            scenario.solvers = solvers.iter().map(|solver| solver.to_config()).collect();
+        }
+        for path in &self.trajectory {
+            // Relative to where it is run from rather than to the scenario
+            let absolute_path =
+                path::absolute(path).map_err(|error| CliError::Io(path.clone(), error))?;
+            scenario.outputs.trajectories.push(TrajectoryOutput {
+                format: TrajectoryFormat::from_path(path),
+                path: absolute_path,
+                interval: self.trajectory_interval,
+            });
+        }
+        scenario.validate().map_err(error)?;
+
+        Ok(scenario)
+    }
+}
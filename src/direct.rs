@@ -1,4 +1,9 @@
-use crate::{Body, BodyID};
+use crate::{
+    Body, BodyID,
+    body::DT,
+    force_law::ForceLaw,
+    post_newtonian::{SPEED_OF_LIGHT, get_corrections},
+};
 use macroquad::prelude::*;
 use std::{
     collections::HashMap,
@@ -10,7 +15,17 @@ pub struct Direct;
 impl Direct {
     pub const COLOR: Color = GREEN;
 
-    pub fn handle(bodies: &mut HashMap<BodyID, Body>) -> Duration {
+    pub fn handle(bodies: &mut HashMap<BodyID, Body>, force_law: ForceLaw) -> Duration {
+        Self::handle_with_speed_of_light(bodies, force_law, *SPEED_OF_LIGHT.read().unwrap())
+    }
+
+    /// The same with the 1PN corrections at `speed_of_light` if there is one,
+    /// whatever `SPEED_OF_LIGHT` is.
+    pub fn handle_with_speed_of_light(
+        bodies: &mut HashMap<BodyID, Body>,
+        force_law: ForceLaw,
+        speed_of_light: Option<f64>,
+    ) -> Duration {
         let start = Instant::now();
 
         let bodies_clone = bodies.clone();
@@ -22,11 +37,36 @@ impl Direct {
                     let lhs = bodies.get_mut(lhs_id).unwrap();
                     let rhs = bodies_clone.get(rhs_id).unwrap();
 
-                    lhs.adjust_speed(rhs.pos, rhs.mass);
+                    lhs.adjust_speed(rhs.pos, force_law.get_source(rhs), force_law);
                 }
             }
         }
 
+        // The corrections are only known for Newtonian gravity
+        if let Some(c) = speed_of_light
+            && matches!(force_law, ForceLaw::Newtonian)
+        {
+            let old_bodies = bodies_keys
+                .iter()
+                .map(|body_id| *bodies_clone.get(body_id).unwrap())
+                .collect::<Vec<_>>();
+            let accelerations = bodies_keys
+                .iter()
+                .zip(&old_bodies)
+                .map(|(body_id, old_body)| {
+                    (bodies.get(body_id).unwrap().speed - old_body.speed) / DT.get()
+                })
+                .collect::<Vec<_>>();
+
+            for (body_id, correction) in
+                bodies_keys
+                    .iter()
+                    .zip(get_corrections(&old_bodies, &accelerations, c))
+            {
+                bodies.get_mut(body_id).unwrap().accelerate(correction);
+            }
+        }
+
         start.elapsed()
     }
 }
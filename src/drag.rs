@@ -0,0 +1,145 @@
+use crate::{
+    body::Body,
+    units::{Quantity, Scales},
+    vector::{Vector, VectorExt, components},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{LazyLock, RwLock};
+
+pub type SpeciesID = usize;
+
+/// The species of the dust fraction of the generated bodies, the others being planetesimals.
+pub const DUST: SpeciesID = 1;
+
+/// The background medium the bodies are dragged against, if any.
+pub static MEDIUM: LazyLock<RwLock<Option<Medium>>> = LazyLock::new(|| RwLock::new(None));
+
+/// Indexed by `Body::species`.
+pub static SPECIES: LazyLock<RwLock<Vec<Species>>> = LazyLock::new(|| {
+    RwLock::new(vec![Species {
+        name: "Bodies",
+        drag: None,
+    }])
+});
+
+/// A gas filling the whole space, rotating in the xy plane about `center`
+/// at the same `circular_speed` at every radius.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Medium {
+    /// The origin if left out.
+    #[serde(default, with = "components")]
+    pub center: Vector,
+    pub circular_speed: f64,
+    pub density: f64,
+    pub thermal_speed: f64,
+    pub mean_free_path: f64,
+}
+
+impl Medium {
+    /// The same with the numbers of the scenario's units in the simulation's.
+    pub fn to_simulation(self, scales: &Scales) -> Self {
+        Self {
+            center: scales.in_simulation(Quantity::Length, 1.0) * self.center,
+            circular_speed: scales.in_simulation(Quantity::Speed, self.circular_speed),
+            density: scales.in_simulation(Quantity::Density, self.density),
+            thermal_speed: scales.in_simulation(Quantity::Speed, self.thermal_speed),
+            mean_free_path: scales.in_simulation(Quantity::Length, self.mean_free_path),
+        }
+    }
+
+    pub fn get_velocity(&self, pos: Vector) -> Vector {
+        let r = pos - self.center;
+        let distance = r.x().hypot(r.y());
+        if distance == 0.0 {
+            return Vector::ZERO;
+        }
+
+        self.circular_speed / distance * Vector::from_xy(-r.y(), r.x())
+    }
+
+    /// The acceleration that takes `body` towards the velocity of the medium
+    /// over `dt`. The relative velocity decays exactly exponentially over the
+    /// step, so that stopping times shorter than `dt` stay stable.
+    pub fn get_drag_acceleration(&self, body: &Body, drag: Drag, dt: f64) -> Vector {
+        let relative_speed = body.speed - self.get_velocity(body.pos);
+        let rate = drag.get_rate(self, body, relative_speed.length());
+
+        -relative_speed * (1.0 - (-rate * dt).exp()) / dt
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Drag {
+    /// `-rate u`, with `u` the velocity relative to the medium.
+    Linear { rate: f64 },
+    /// `-coefficient |u| u`.
+    Quadratic { coefficient: f64 },
+    /// Bodies smaller than the mean free path: the stopping time is
+    /// `rho_s s / (rho_g v_th)`, with `rho_s` the density of the body and `s` its radius.
+    #[default]
+    Epstein,
+    /// Bodies larger than the mean free path: the stopping time is
+    /// `2 rho_s s^2 / (9 rho_g nu)`, with the viscosity `nu = v_th lambda / 2`.
+    Stokes,
+}
+
+impl Drag {
+    /// The same with the numbers of the scenario's units in the simulation's.
+    pub fn to_simulation(self, scales: &Scales) -> Self {
+        match self {
+            Self::Linear { rate } => Self::Linear {
+                rate: scales.in_simulation(Quantity::Frequency, rate),
+            },
+            Self::Quadratic { coefficient } => Self::Quadratic {
+                coefficient: scales.in_simulation(Quantity::InverseLength, coefficient),
+            },
+            Self::Epstein | Self::Stokes => self,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Linear { .. } => "Linear",
+            Self::Quadratic { .. } => "Quadratic",
+            Self::Epstein => "Epstein",
+            Self::Stokes => "Stokes",
+        }
+    }
+
+    /// The inverse of the stopping time of `body` moving at `relative_speed`
+    /// through `medium`.
+    pub fn get_rate(&self, medium: &Medium, body: &Body, relative_speed: f64) -> f64 {
+        match *self {
+            Self::Linear { rate } => rate,
+            Self::Quadratic { coefficient } => coefficient * relative_speed,
+            Self::Epstein => medium.density * medium.thermal_speed / (body.density * body.radius),
+            Self::Stokes => {
+                let viscosity = medium.thermal_speed * medium.mean_free_path / 2.0;
+                9.0 * medium.density * viscosity / (2.0 * body.density * body.radius.powi(2))
+            }
+        }
+    }
+}
+
+/// A kind of body, with the forces beyond gravity it feels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Species {
+    pub name: &'static str,
+    pub drag: Option<Drag>,
+}
+
+/// Planetesimals, and dust dragged by the medium with `dust_drag`.
+pub fn set_species(dust_drag: Drag) {
+    *SPECIES.write().unwrap() = vec![
+        Species {
+            name: "Planetesimals",
+            drag: None,
+        },
+        Species {
+            name: "Dust",
+            drag: Some(dust_drag),
+        },
+    ];
+}
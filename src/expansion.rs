@@ -0,0 +1,75 @@
+use crate::{
+    force_law::ForceLaw,
+    vector::{DIMENSIONS, Vector, VectorExt},
+};
+
+/// The far field acting around `center`, per unit response, expanded to first order:
+/// `a(x) = acceleration + jacobian * (x - center)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalExpansion {
+    pub center: Vector,
+    pub acceleration: Vector,
+    pub jacobian: [[f64; DIMENSIONS]; DIMENSIONS],
+}
+
+impl LocalExpansion {
+    pub fn new(center: Vector) -> Self {
+        Self {
+            center,
+            acceleration: Vector::ZERO,
+            jacobian: [[0.0; DIMENSIONS]; DIMENSIONS],
+        }
+    }
+
+    /// Adds the field of a point `source` at `pos`. For the field `s g(|r|) r`,
+    /// with `g(d) = f(d) / d` and `f` the magnitude, the Jacobian by the
+    /// evaluation point is `-s (g I + g'(|r|) r r^T / |r|)`.
+    pub fn add(&mut self, pos: Vector, source: f64, force_law: ForceLaw) {
+        let r = pos - self.center;
+        let distance = r.length();
+        let magnitude = force_law.get_magnitude(distance);
+
+        let g = magnitude / distance;
+        let g_derivative = (force_law.get_magnitude_derivative(distance) - g) / distance;
+
+        self.acceleration += source * g * r;
+
+        let r = r.components();
+        for (i, row) in self.jacobian.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value -=
+                    source * (if i == j { g } else { 0.0 } + g_derivative * r[i] * r[j] / distance);
+            }
+        }
+    }
+
+    /// The same field expanded around another point.
+    pub fn shift(&self, center: Vector) -> Self {
+        Self {
+            center,
+            acceleration: self.evaluate(center),
+            jacobian: self.jacobian,
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        let other = other.shift(self.center);
+
+        self.acceleration += other.acceleration;
+        for (row, other_row) in self.jacobian.iter_mut().zip(other.jacobian) {
+            for (value, other_value) in row.iter_mut().zip(other_row) {
+                *value += other_value;
+            }
+        }
+    }
+
+    pub fn evaluate(&self, pos: Vector) -> Vector {
+        let d = (pos - self.center).components();
+
+        self.acceleration
+            + Vector::from_components(
+                self.jacobian
+                    .map(|row| row.iter().zip(d).map(|(value, d)| value * d).sum()),
+            )
+    }
+}
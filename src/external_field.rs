@@ -0,0 +1,258 @@
+use crate::{
+    body::G,
+    units::{Quantity, Scales},
+    vector::{Vector, VectorExt, components},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{LazyLock, RwLock};
+
+/// The background potential the bodies are embedded in, the sum of its parts.
+pub static EXTERNAL_FIELDS: LazyLock<RwLock<Vec<ExternalField>>> =
+    LazyLock::new(|| RwLock::new(Vec::new()));
+
+/// A fixed background potential, felt by every body but unaffected by them.
+/// Scenarios give the vectors as lists of components, the positions being the
+/// origin if left out.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ExternalField {
+    PointMass {
+        #[serde(default, with = "components")]
+        pos: Vector,
+        mass: f64,
+    },
+    /// The potential `-G M / sqrt(r^2 + b^2)`, with `b` being `radius`.
+    Plummer {
+        #[serde(default, with = "components")]
+        pos: Vector,
+        mass: f64,
+        radius: f64,
+    },
+    /// The potential `v0^2 ln(r^2 + rc^2) / 2`, giving the flat rotation curve `v0`
+    /// outside the core of radius `rc`.
+    LogarithmicHalo {
+        #[serde(default, with = "components")]
+        pos: Vector,
+        circular_speed: f64,
+        core_radius: f64,
+    },
+    Uniform {
+        #[serde(with = "components")]
+        acceleration: Vector,
+    },
+    /// A bar in the xy plane turning at `pattern_speed` about `pos`,
+    /// approximated by two Plummer spheres at its ends.
+    RotatingBar {
+        #[serde(default, with = "components")]
+        pos: Vector,
+        mass: f64,
+        half_length: f64,
+        radius: f64,
+        pattern_speed: f64,
+    },
+    /// The tidal field of a distant point mass, `G M / R^3 (3 n n^T - I) (x - pos)`,
+    /// as seen from `pos` with the host lying along `direction`, and
+    /// `strength` being `G M / R^3`.
+    Tidal {
+        #[serde(default, with = "components")]
+        pos: Vector,
+        #[serde(with = "components")]
+        direction: Vector,
+        strength: f64,
+    },
+}
+
+impl ExternalField {
+    /// The same with the numbers of the scenario's units in the simulation's,
+    /// and the direction of a `Tidal` field made a unit vector.
+    pub fn to_simulation(self, scales: &Scales) -> Self {
+        let length = |value| scales.in_simulation(Quantity::Length, value);
+        let mass = |value| scales.in_simulation(Quantity::Mass, value);
+        let length_scale = length(1.0);
+
+        match self {
+            Self::PointMass { pos, mass: m } => Self::PointMass {
+                pos: length_scale * pos,
+                mass: mass(m),
+            },
+            Self::Plummer {
+                pos,
+                mass: m,
+                radius,
+            } => Self::Plummer {
+                pos: length_scale * pos,
+                mass: mass(m),
+                radius: length(radius),
+            },
+            Self::LogarithmicHalo {
+                pos,
+                circular_speed,
+                core_radius,
+            } => Self::LogarithmicHalo {
+                pos: length_scale * pos,
+                circular_speed: scales.in_simulation(Quantity::Speed, circular_speed),
+                core_radius: length(core_radius),
+            },
+            Self::Uniform { acceleration } => Self::Uniform {
+                acceleration: scales.in_simulation(Quantity::Acceleration, 1.0) * acceleration,
+            },
+            Self::RotatingBar {
+                pos,
+                mass: m,
+                half_length,
+                radius,
+                pattern_speed,
+            } => Self::RotatingBar {
+                pos: length_scale * pos,
+                mass: mass(m),
+                half_length: length(half_length),
+                radius: length(radius),
+                pattern_speed: scales.in_simulation(Quantity::Frequency, pattern_speed),
+            },
+            Self::Tidal {
+                pos,
+                direction,
+                strength,
+            } => Self::Tidal {
+                pos: length_scale * pos,
+                direction: direction / direction.length(),
+                strength: scales.in_simulation(Quantity::SquaredFrequency, strength),
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PointMass { .. } => "Point mass",
+            Self::Plummer { .. } => "Plummer sphere",
+            Self::LogarithmicHalo { .. } => "Logarithmic halo",
+            Self::Uniform { .. } => "Uniform field",
+            Self::RotatingBar { .. } => "Rotating bar",
+            Self::Tidal { .. } => "Tidal field",
+        }
+    }
+
+    /// The ends of a `RotatingBar` at `time`.
+    fn get_bar_ends(pos: Vector, half_length: f64, pattern_speed: f64, time: f64) -> [Vector; 2] {
+        let end = Vector::from_xy(half_length, 0.0).rotate_xy(pattern_speed * time);
+
+        [pos + end, pos - end]
+    }
+
+    pub fn get_acceleration(&self, at: Vector, time: f64) -> Vector {
+        match *self {
+            Self::PointMass { pos, mass } => {
+                let r = pos - at;
+                if r == Vector::ZERO {
+                    return Vector::ZERO;
+                }
+
+                G.get() * mass * r / r.length().powi(3)
+            }
+            Self::Plummer { pos, mass, radius } => {
+                let r = pos - at;
+                G.get() * mass * r / (r.dot(r) + radius.powi(2)).powf(1.5)
+            }
+            Self::LogarithmicHalo {
+                pos,
+                circular_speed,
+                core_radius,
+            } => {
+                let r = pos - at;
+                circular_speed.powi(2) * r / (r.dot(r) + core_radius.powi(2))
+            }
+            Self::Uniform { acceleration } => acceleration,
+            Self::RotatingBar {
+                pos,
+                mass,
+                half_length,
+                radius,
+                pattern_speed,
+            } => Self::get_bar_ends(pos, half_length, pattern_speed, time)
+                .into_iter()
+                .map(|end| {
+                    Self::Plummer {
+                        pos: end,
+                        mass: mass / 2.0,
+                        radius,
+                    }
+                    .get_acceleration(at, time)
+                })
+                .sum(),
+            Self::Tidal {
+                pos,
+                direction,
+                strength,
+            } => {
+                let d = at - pos;
+                strength * (3.0 * d.dot(direction) * direction - d)
+            }
+        }
+    }
+
+    /// The potential per unit mass at `at`.
+    pub fn get_potential(&self, at: Vector, time: f64) -> f64 {
+        match *self {
+            Self::PointMass { pos, mass } => {
+                let distance = (pos - at).length();
+                if distance == 0.0 {
+                    return 0.0;
+                }
+
+                -G.get() * mass / distance
+            }
+            Self::Plummer { pos, mass, radius } => {
+                let r = pos - at;
+                -G.get() * mass / (r.dot(r) + radius.powi(2)).sqrt()
+            }
+            Self::LogarithmicHalo {
+                pos,
+                circular_speed,
+                core_radius,
+            } => {
+                let r = pos - at;
+                circular_speed.powi(2) * (r.dot(r) + core_radius.powi(2)).ln() / 2.0
+            }
+            Self::Uniform { acceleration } => -acceleration.dot(at),
+            Self::RotatingBar {
+                pos,
+                mass,
+                half_length,
+                radius,
+                pattern_speed,
+            } => Self::get_bar_ends(pos, half_length, pattern_speed, time)
+                .into_iter()
+                .map(|end| {
+                    Self::Plummer {
+                        pos: end,
+                        mass: mass / 2.0,
+                        radius,
+                    }
+                    .get_potential(at, time)
+                })
+                .sum(),
+            Self::Tidal {
+                pos,
+                direction,
+                strength,
+            } => {
+                let d = at - pos;
+                -strength * (3.0 * d.dot(direction).powi(2) - d.dot(d)) / 2.0
+            }
+        }
+    }
+}
+
+pub fn get_external_acceleration(fields: &[ExternalField], at: Vector, time: f64) -> Vector {
+    fields
+        .iter()
+        .map(|field| field.get_acceleration(at, time))
+        .sum()
+}
+
+pub fn get_external_potential(fields: &[ExternalField], at: Vector, time: f64) -> f64 {
+    fields
+        .iter()
+        .map(|field| field.get_potential(at, time))
+        .sum()
+}
@@ -0,0 +1,241 @@
+#[cfg(not(feature = "3d"))]
+use crate::vector::VectorExt;
+use crate::{
+    body::{Body, DEFAULT_G, G},
+    units::{Quantity, Scales},
+    vector::Vector,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+use std::sync::{LazyLock, RwLock};
+
+/// The law the simulations step with, read once per step.
+pub static FORCE_LAW: LazyLock<RwLock<ForceLaw>> =
+    LazyLock::new(|| RwLock::new(ForceLaw::default()));
+
+/// The distance at which the logarithmic law pulls as hard as the Newtonian one,
+/// and at which the logarithmic potential is zero.
+pub const LOGARITHMIC_SCALE: f64 = 100.0;
+/// The Coulomb constant, as strong as gravity between unit charges of unit mass
+/// under the default `G`.
+pub const COULOMB_K: f64 = DEFAULT_G;
+pub const YUKAWA_LENGTH: f64 = 100.0;
+/// The softening length of the example user-supplied law.
+const SOFTENING: f64 = 5.0;
+
+/// The user-supplied laws, which scenarios and snapshots refer to by name.
+pub const RADIAL_LAWS: [RadialLaw; 1] = [RadialLaw {
+    name: "Softened Newtonian",
+    coupling: Coupling::Mass,
+    field: |distance| G.get() * distance / (distance.powi(2) + SOFTENING.powi(2)).powf(1.5),
+    potential: |distance| -G.get() / (distance.powi(2) + SOFTENING.powi(2)).sqrt(),
+}];
+
+/// What the bodies interact through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coupling {
+    Mass,
+    /// Signed, so that like charges repel and opposite charges attract.
+    Charge,
+}
+
+/// A law given by the magnitude of the field of a unit source at a distance,
+/// positive meaning attraction, and the potential it derives from. The functions
+/// are in the simulation's units, whatever the scenario's.
+#[derive(Clone, Copy, Debug)]
+pub struct RadialLaw {
+    pub name: &'static str,
+    pub coupling: Coupling,
+    pub field: fn(f64) -> f64,
+    pub potential: fn(f64) -> f64,
+}
+
+/// Scenarios give the user-supplied laws by name, among `RADIAL_LAWS`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ForceLaw {
+    /// `G m / r^2` with the potential `-G m / r`, also applied in the plane.
+    #[default]
+    Newtonian,
+    /// The genuine 2D gravity `G m / r` with the potential `G m ln(r / r0) / r0`,
+    /// `r0` being `LOGARITHMIC_SCALE`.
+    Logarithmic,
+    /// `-k q / r^2` between signed charges, with the potential `k q / r`.
+    Coulomb,
+    /// Gravity screened over `length`, with the potential `-G m exp(-r / length) / r`.
+    Yukawa {
+        length: f64,
+    },
+    Radial(RadialLaw),
+}
+
+impl Serialize for RadialLaw {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for RadialLaw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        RADIAL_LAWS
+            .into_iter()
+            .find(|radial_law| radial_law.name == name)
+            .ok_or_else(|| D::Error::custom(format!("unknown force law `{}`", name)))
+    }
+}
+
+impl ForceLaw {
+    /// The same with the lengths of the scenario's units in the simulation's.
+    pub fn to_simulation(self, scales: &Scales) -> Self {
+        match self {
+            Self::Yukawa { length } => Self::Yukawa {
+                length: scales.in_simulation(Quantity::Length, length),
+            },
+            _ => self,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Newtonian => "Newtonian",
+            Self::Logarithmic => "Logarithmic",
+            Self::Coulomb => "Coulomb",
+            Self::Yukawa { .. } => "Yukawa",
+            Self::Radial(radial_law) => radial_law.name,
+        }
+    }
+
+    pub fn get_coupling(&self) -> Coupling {
+        match self {
+            Self::Coulomb => Coupling::Charge,
+            Self::Radial(radial_law) => radial_law.coupling,
+            _ => Coupling::Mass,
+        }
+    }
+
+    /// How strongly `body` acts on the others.
+    pub fn get_source(&self, body: &Body) -> f64 {
+        match self.get_coupling() {
+            Coupling::Mass => body.mass,
+            Coupling::Charge => body.charge,
+        }
+    }
+
+    /// The acceleration of `body` per unit field.
+    pub fn get_response(&self, body: &Body) -> f64 {
+        match self.get_coupling() {
+            Coupling::Mass => 1.0,
+            Coupling::Charge => body.charge / body.mass,
+        }
+    }
+
+    /// The magnitude of the field of a unit source at `distance`, positive meaning attraction.
+    pub fn get_magnitude(&self, distance: f64) -> f64 {
+        match self {
+            Self::Newtonian => G.get() / distance.powi(2),
+            Self::Logarithmic => G.get() / (LOGARITHMIC_SCALE * distance),
+            Self::Coulomb => -COULOMB_K / distance.powi(2),
+            Self::Yukawa { length } => {
+                G.get()
+                    * (-distance / length).exp()
+                    * (1.0 / distance.powi(2) + 1.0 / (length * distance))
+            }
+            Self::Radial(radial_law) => (radial_law.field)(distance),
+        }
+    }
+
+    /// The derivative of `get_magnitude` by the distance.
+    pub fn get_magnitude_derivative(&self, distance: f64) -> f64 {
+        match self {
+            Self::Newtonian => -2.0 * G.get() / distance.powi(3),
+            Self::Logarithmic => -G.get() / (LOGARITHMIC_SCALE * distance.powi(2)),
+            Self::Coulomb => 2.0 * COULOMB_K / distance.powi(3),
+            Self::Yukawa { length } => {
+                -G.get()
+                    * (-distance / length).exp()
+                    * (2.0 / distance.powi(3)
+                        + 2.0 / (length * distance.powi(2))
+                        + 1.0 / (length.powi(2) * distance))
+            }
+            // A user-supplied law only gives the field, so it is differentiated numerically
+            Self::Radial(radial_law) => {
+                let h = distance * 1e-6;
+                ((radial_law.field)(distance + h) - (radial_law.field)(distance - h)) / (2.0 * h)
+            }
+        }
+    }
+
+    /// The field caused at `at` by a `source` at `pos`.
+    pub fn get_field(&self, at: Vector, pos: Vector, source: f64) -> Vector {
+        let r = pos - at;
+        // Coincident bodies pull in no particular direction
+        if r == Vector::ZERO {
+            return Vector::ZERO;
+        }
+
+        let distance = r.length();
+        source * self.get_magnitude(distance) / distance * r
+    }
+
+    /// The potential energy of a pair of unit sources at `distance`.
+    pub fn get_potential(&self, distance: f64) -> f64 {
+        match self {
+            Self::Newtonian => -G.get() / distance,
+            Self::Logarithmic => G.get() * (distance / LOGARITHMIC_SCALE).ln() / LOGARITHMIC_SCALE,
+            Self::Coulomb => COULOMB_K / distance,
+            Self::Yukawa { length } => -G.get() * (-distance / length).exp() / distance,
+            Self::Radial(radial_law) => (radial_law.potential)(distance),
+        }
+    }
+}
+
+/// A point source standing in for a group of bodies.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Monopole {
+    pub source: f64,
+    pub pos: Vector,
+}
+
+/// The sources of a group of bodies split by sign, so that opposite charges
+/// keep centres of their own instead of cancelling into a meaningless one.
+/// With masses only `positive` is ever used.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sources {
+    pub positive: Monopole,
+    pub negative: Monopole,
+}
+
+impl Sources {
+    /// Until `finish` is called, the positions hold source-weighted sums.
+    pub fn add(&mut self, pos: Vector, source: f64) {
+        let monopole = if source >= 0.0 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+
+        monopole.source += source;
+        monopole.pos += source * pos;
+    }
+
+    pub fn add_sources(&mut self, other: &Self) {
+        for monopole in other.get_monopoles() {
+            self.add(monopole.pos, monopole.source);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        for monopole in [&mut self.positive, &mut self.negative] {
+            if monopole.source != 0.0 {
+                monopole.pos /= monopole.source;
+            }
+        }
+    }
+
+    /// The non-empty monopoles.
+    pub fn get_monopoles(&self) -> impl Iterator<Item = &Monopole> {
+        [&self.positive, &self.negative]
+            .into_iter()
+            .filter(|monopole| monopole.source != 0.0)
+    }
+}
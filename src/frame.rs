@@ -0,0 +1,119 @@
+use crate::{
+    body::{Body, BodyID},
+    external_field::ExternalField,
+    vector::{Vector, VectorExt},
+};
+use std::collections::HashMap;
+
+/// A frame turning in the xy plane about `center` at `angular_speed`,
+/// in which the bodies feel the Coriolis and the centrifugal force.
+/// External fields and the medium are still given in the inertial frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RotatingFrame {
+    pub center: Vector,
+    pub angular_speed: f64,
+    /// How far the frame has turned relative to the inertial one.
+    pub angle: f64,
+}
+
+impl RotatingFrame {
+    /// The frame turning with the mean rotation of `bodies` about their centre of mass,
+    /// `L / I`, so that a binary stays put. It is aligned with the inertial frame at time zero.
+    pub fn new_corotating(bodies: &HashMap<BodyID, Body>, time: f64) -> Self {
+        let total_mass = bodies.values().map(|body| body.mass).sum::<f64>();
+        let center = bodies
+            .values()
+            .map(|body| body.mass * body.pos)
+            .sum::<Vector>()
+            / total_mass;
+
+        let (angular_momentum, moment_of_inertia) =
+            bodies.values().fold((0.0, 0.0), |(l, i), body| {
+                let r = (body.pos - center).components();
+                let v = body.speed.components();
+
+                (
+                    l + body.mass * (r[0] * v[1] - r[1] * v[0]),
+                    i + body.mass * (r[0].powi(2) + r[1].powi(2)),
+                )
+            });
+
+        let angular_speed = if moment_of_inertia != 0.0 {
+            angular_momentum / moment_of_inertia
+        } else {
+            0.0
+        };
+
+        Self {
+            center,
+            angular_speed,
+            angle: angular_speed * time,
+        }
+    }
+
+    /// The frame in which the bar among `external_fields` stands still if there is one,
+    /// or else the one turning with `bodies`.
+    pub fn new_standing(
+        external_fields: &[ExternalField],
+        bodies: &HashMap<BodyID, Body>,
+        time: f64,
+    ) -> Self {
+        external_fields
+            .iter()
+            .find_map(|external_field| match *external_field {
+                ExternalField::RotatingBar {
+                    pos, pattern_speed, ..
+                } => Some(Self {
+                    center: pos,
+                    angular_speed: pattern_speed,
+                    angle: pattern_speed * time,
+                }),
+                _ => None,
+            })
+            .unwrap_or_else(|| Self::new_corotating(bodies, time))
+    }
+
+    /// `Ω × r` with `Ω` along the z axis, `r` being taken from `center`.
+    fn get_frame_speed(&self, pos: Vector) -> Vector {
+        let r = pos - self.center;
+        self.angular_speed * Vector::from_xy(-r.y(), r.x())
+    }
+
+    /// The Coriolis and the centrifugal acceleration of a body at `pos` moving at `speed`
+    /// in the frame over `dt`: `-2 Ω × v - Ω × (Ω × r)`. The Coriolis force turns the
+    /// velocity exactly over the step, so that it doesn't pump energy into the bodies.
+    pub fn get_fictitious_acceleration(&self, pos: Vector, speed: Vector, dt: f64) -> Vector {
+        let coriolis = (speed.rotate_xy(-2.0 * self.angular_speed * dt) - speed) / dt;
+
+        let r = pos - self.center;
+        coriolis + self.angular_speed.powi(2) * Vector::from_xy(r.x(), r.y())
+    }
+
+    pub fn to_inertial_pos(self, pos: Vector) -> Vector {
+        self.center + (pos - self.center).rotate_xy(self.angle)
+    }
+
+    pub fn to_inertial(self, body: &Body) -> Body {
+        Body {
+            pos: self.to_inertial_pos(body.pos),
+            speed: (body.speed + self.get_frame_speed(body.pos)).rotate_xy(self.angle),
+            ..*body
+        }
+    }
+
+    pub fn to_rotating(self, body: &Body) -> Body {
+        let pos = self.center + (body.pos - self.center).rotate_xy(-self.angle);
+
+        Body {
+            pos,
+            speed: body.speed.rotate_xy(-self.angle) - self.get_frame_speed(pos),
+            ..*body
+        }
+    }
+
+    /// The centrifugal potential per unit mass, `-Ω^2 R^2 / 2` with `R` the distance from the axis.
+    pub fn get_centrifugal_potential(&self, pos: Vector) -> f64 {
+        let r = (pos - self.center).components();
+        -(self.angular_speed * r[0].hypot(r[1])).powi(2) / 2.0
+    }
+}
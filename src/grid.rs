@@ -1,128 +1,432 @@
-use crate::{BORDER_COLOR, BORDER_THICKNESS, Body, BodyID, Zoom, body::get_rectangle};
+use crate::{
+    BORDER_COLOR, BORDER_THICKNESS, Body, BodyID, Camera,
+    body::get_rectangle,
+    expansion::LocalExpansion,
+    force_law::{ForceLaw, Sources},
+    orbit::draw_box,
+    vector::{DIMENSIONS, ORTHANTS_N, Vector, VectorExt},
+};
 use macroquad::prelude::*;
-use num_complex::{Complex, ComplexFloat};
 use std::{
+    array::from_fn,
     collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    sync::{LazyLock, RwLock},
     time::{Duration, Instant},
 };
 
-pub const TAU: f64 = 0.3;
+pub static TAU: LazyLock<RwLock<f64>> = LazyLock::new(|| RwLock::new(0.3));
+const DELTA_TAU: f64 = 0.05;
+pub const TAU_RANGE: RangeInclusive<f64> = 0.05..=3.0;
+/// Whether to draw the cells.
+pub static DRAW: LazyLock<RwLock<bool>> = LazyLock::new(|| RwLock::new(false));
+
+pub static SIZING: LazyLock<RwLock<GridSizing>> =
+    LazyLock::new(|| RwLock::new(GridSizing::Uniform));
+
+/// How many steps the duration per body is averaged over before TAU is moved.
+const TUNING_LENGTH: usize = 20;
+/// How much slower than the previous average the grid must get before TAU turns
+/// around, so that the noise between frames doesn't flip it back and forth.
+const TUNING_TOLERANCE: f64 = 0.05;
+static TAU_TUNER: LazyLock<RwLock<TauTuner>> = LazyLock::new(|| {
+    RwLock::new(TauTuner {
+        previous_average: f64::INFINITY,
+        adjustment: TauAdjustment::Increase,
+        total: 0.0,
+        n: 0,
+    })
+});
 
 #[derive(Clone)]
 pub struct Cell {
+    /// The index of the cell along every axis.
+    pub indices: [usize; DIMENSIONS],
     pub bodies: HashSet<BodyID>,
     pub total_mass: f64,
-    pub pos: Complex<f64>,
+    pub pos: Vector,
+    pub sources: Sources,
 }
 
 impl Cell {
-    pub fn add_body(&mut self, body_id: Instant, bodies: &HashMap<BodyID, Body>) {
+    pub fn add_body(
+        &mut self,
+        body_id: BodyID,
+        bodies: &HashMap<BodyID, Body>,
+        force_law: ForceLaw,
+    ) {
         self.bodies.insert(body_id);
 
         let body = bodies.get(&body_id).unwrap();
         self.total_mass += body.mass;
         self.pos += body.mass * body.pos;
+        self.sources.add(body.pos, force_law.get_source(body));
     }
 
     pub fn set_pos(&mut self) {
         if self.total_mass != 0.0 {
             self.pos /= self.total_mass;
         }
+        self.sources.finish();
+    }
+}
+
+/// The state of the TAU hill-climbing, which compares averages over
+/// `TUNING_LENGTH` steps rather than single durations.
+struct TauTuner {
+    /// The average duration per body with the previous TAU.
+    previous_average: f64,
+    /// The direction TAU was last moved in.
+    adjustment: TauAdjustment,
+    /// The durations per body with the current TAU.
+    total: f64,
+    n: usize,
+}
+
+#[derive(Clone, Copy)]
+pub enum TauAdjustment {
+    Increase = 1,
+    Decrease = -1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GridSizing {
+    /// Cells of equal size spanning the bounding rectangle.
+    Uniform,
+    /// Edges placed at the quantiles of the body coordinates, so that every
+    /// row and every column (and layer) holds roughly the same number of bodies.
+    Quantile,
+}
+
+impl GridSizing {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Uniform => "Uniform",
+            Self::Quantile => "Quantile",
+        }
+    }
+}
+
+/// Returns `n + 1` edges, the first and the last being `start` and `end`.
+fn get_uniform_edges(start: f64, end: f64, n: usize) -> Vec<f64> {
+    (0..=n)
+        .map(|k| start + (end - start) * k as f64 / n as f64)
+        .collect()
+}
+
+/// Returns `n + 1` edges splitting `coords` into `n` groups of roughly equal size.
+fn get_quantile_edges(start: f64, end: f64, n: usize, mut coords: Vec<f64>) -> Vec<f64> {
+    coords.sort_unstable_by(f64::total_cmp);
+
+    let mut edges = Vec::with_capacity(n + 1);
+    edges.push(start);
+    for k in 1..n {
+        edges.push(coords[k * coords.len() / n]);
+    }
+    edges.push(end);
+
+    edges
+}
+
+/// The cells within one step of `indices` along every axis, `indices` included.
+pub fn get_stencil(
+    indices: [usize; DIMENSIONS],
+    counts: [usize; DIMENSIONS],
+) -> impl Iterator<Item = [usize; DIMENSIONS]> {
+    (0..3usize.pow(DIMENSIONS as u32)).filter_map(move |offset| {
+        let mut neighbor = indices;
+        for (k, index) in neighbor.iter_mut().enumerate() {
+            *index = (*index + offset / 3usize.pow(k as u32) % 3).checked_sub(1)?;
+            if *index >= counts[k] {
+                return None;
+            }
+        }
+
+        Some(neighbor)
+    })
+}
+
+/// The index of the cell between two consecutive `edges` containing `coord`,
+/// clamped so that bodies on the outer edges still land in a cell.
+pub fn get_cell_index(edges: &[f64], coord: f64) -> usize {
+    edges
+        .partition_point(|edge| *edge <= coord)
+        .saturating_sub(1)
+        .min(edges.len() - 2)
+}
+
+/// Whether the cells at `indices` and `rhs_indices` are within each other's 3x3 stencil.
+fn is_adjacent(indices: [usize; DIMENSIONS], rhs_indices: [usize; DIMENSIONS]) -> bool {
+    indices
+        .iter()
+        .zip(rhs_indices)
+        .all(|(index, rhs_index)| index.abs_diff(rhs_index) <= 1)
+}
+
+/// The cells of the level below that the cell at `indices` was merged from.
+fn get_children(
+    indices: [usize; DIMENSIONS],
+    counts: [usize; DIMENSIONS],
+) -> impl Iterator<Item = [usize; DIMENSIONS]> {
+    (0..ORTHANTS_N).filter_map(move |orthant| {
+        let child: [usize; DIMENSIONS] = from_fn(|k| 2 * indices[k] + (orthant >> k & 1));
+        child
+            .iter()
+            .zip(counts)
+            .all(|(index, count)| *index < count)
+            .then_some(child)
+    })
+}
+
+/// The cells of one level of the far-field pass, the finest one being the grid itself.
+struct Level {
+    counts: [usize; DIMENSIONS],
+    strides: [usize; DIMENSIONS],
+    cells: Vec<Cell>,
+}
+
+impl Level {
+    fn get_flat_index(&self, indices: [usize; DIMENSIONS]) -> usize {
+        indices
+            .iter()
+            .zip(self.strides)
+            .map(|(index, stride)| index * stride)
+            .sum()
+    }
+
+    /// The level above, every cell merging up to two cells along every axis.
+    fn coarsen(&self) -> Self {
+        let counts: [usize; DIMENSIONS] = from_fn(|k| self.counts[k].div_ceil(2));
+        let strides: [usize; DIMENSIONS] = from_fn(|k| counts[..k].iter().product());
+
+        let mut level = Self {
+            counts,
+            strides,
+            cells: (0..counts.iter().product())
+                .map(|flat_index| Cell {
+                    indices: from_fn(|k| flat_index / strides[k] % counts[k]),
+                    bodies: HashSet::new(),
+                    total_mass: 0.0,
+                    pos: Vector::ZERO,
+                    sources: Sources::default(),
+                })
+                .collect(),
+        };
+
+        for cell in &self.cells {
+            let flat_index = level.get_flat_index(cell.indices.map(|index| index / 2));
+            let parent = &mut level.cells[flat_index];
+            parent.total_mass += cell.total_mass;
+            parent.pos += cell.total_mass * cell.pos;
+            parent.sources.add_sources(&cell.sources);
+        }
+        for cell in level.cells.iter_mut() {
+            cell.set_pos();
+        }
+
+        level
     }
 }
 
 pub struct Grid;
 
 impl Grid {
-    pub const DRAW: bool = false;
     pub const COLOR: Color = BLUE;
 
-    pub fn handle(bodies: &mut HashMap<BodyID, Body>, zoom: &Zoom) -> Duration {
+    pub fn adjust_tau(adjustment: TauAdjustment) {
+        let mut write = TAU.write().unwrap();
+        *write += DELTA_TAU * adjustment as isize as f64;
+        *write = write.clamp(*TAU_RANGE.start(), *TAU_RANGE.end());
+    }
+
+    /// Hill-climbs TAU towards the fastest grid: averages the duration per body
+    /// over `TUNING_LENGTH` steps, then keeps moving TAU in the same direction
+    /// unless the average got slower by more than `TUNING_TOLERANCE`.
+    pub fn tune_tau(duration: f64) {
+        let mut tuner = TAU_TUNER.write().unwrap();
+        tuner.total += duration;
+        tuner.n += 1;
+        if tuner.n < TUNING_LENGTH {
+            return;
+        }
+
+        let average = tuner.total / tuner.n as f64;
+        if average > tuner.previous_average * (1.0 + TUNING_TOLERANCE) {
+            tuner.adjustment = match tuner.adjustment {
+                TauAdjustment::Increase => TauAdjustment::Decrease,
+                TauAdjustment::Decrease => TauAdjustment::Increase,
+            };
+        }
+        tuner.previous_average = average;
+        tuner.total = 0.0;
+        tuner.n = 0;
+
+        Self::adjust_tau(tuner.adjustment);
+    }
+
+    pub fn toggle_sizing() {
+        let mut write = SIZING.write().unwrap();
+        *write = match *write {
+            GridSizing::Uniform => GridSizing::Quantile,
+            GridSizing::Quantile => GridSizing::Uniform,
+        };
+    }
+
+    pub fn handle(
+        bodies: &mut HashMap<BodyID, Body>,
+        force_law: ForceLaw,
+        camera: &Camera,
+    ) -> Duration {
         let start = Instant::now();
 
         let rectangle = get_rectangle(bodies);
+        let min = rectangle.top_left.components();
+        let max = rectangle.bottom_right.components();
 
-        let width = rectangle.bottom_right.re() - rectangle.top_left.re();
-        let height = rectangle.bottom_right.im() - rectangle.top_left.im();
+        let tau = *TAU.read().unwrap();
 
-        let target_size = ((TAU * width * height) / (bodies.len() as f64).sqrt()).sqrt();
+        let volume = (0..DIMENSIONS).map(|k| max[k] - min[k]).product::<f64>();
+        let target_size =
+            ((tau * volume) / (bodies.len() as f64).sqrt()).powf(1.0 / DIMENSIONS as f64);
 
-        let rows_n = ((height / target_size).round() as usize).max(1);
-        let cell_height = height / (rows_n as f64);
+        let counts: [usize; DIMENSIONS] =
+            from_fn(|k| (((max[k] - min[k]) / target_size).round() as usize).max(1));
+        let strides: [usize; DIMENSIONS] = from_fn(|k| counts[..k].iter().product());
+        let get_flat_index = |indices: [usize; DIMENSIONS]| -> usize {
+            indices
+                .iter()
+                .zip(strides)
+                .map(|(index, stride)| index * stride)
+                .sum()
+        };
 
-        let columns_n = ((width / cell_height).round() as usize).max(1);
-        let cell_width = width / (columns_n as f64);
+        let sizing = *SIZING.read().unwrap();
+        let edges: [Vec<f64>; DIMENSIONS] = from_fn(|k| match sizing {
+            GridSizing::Uniform => get_uniform_edges(min[k], max[k], counts[k]),
+            GridSizing::Quantile => get_quantile_edges(
+                min[k],
+                max[k],
+                counts[k],
+                bodies
+                    .values()
+                    .map(|body| body.pos.components()[k])
+                    .collect(),
+            ),
+        });
 
-        let mut cells = vec![
-            vec![
-                Cell {
-                    bodies: HashSet::with_capacity((TAU * (bodies.len() as f64).sqrt()) as usize),
-                    total_mass: 0.0,
-                    pos: Complex::ZERO,
-                };
-                columns_n
-            ];
-            rows_n
-        ];
+        let cells_n = counts.iter().product();
+        let mut cells = Vec::with_capacity(cells_n);
+        for flat_index in 0..cells_n {
+            cells.push(Cell {
+                indices: from_fn(|k| flat_index / strides[k] % counts[k]),
+                bodies: HashSet::with_capacity((tau * (bodies.len() as f64).sqrt()) as usize),
+                total_mass: 0.0,
+                pos: Vector::ZERO,
+                sources: Sources::default(),
+            });
+        }
 
         for (body_id, body) in bodies.iter() {
-            cells[((body.pos.im() - rectangle.top_left.im()) / cell_height) as usize]
-                [((body.pos.re() - rectangle.top_left.re()) / cell_width) as usize]
-                .add_body(*body_id, bodies);
+            let components = body.pos.components();
+
+            cells[get_flat_index(from_fn(|k| get_cell_index(&edges[k], components[k])))]
+                .add_body(*body_id, bodies, force_law);
         }
 
-        for cell in cells.iter_mut().flatten() {
+        for cell in cells.iter_mut() {
             cell.set_pos()
         }
 
-        let bodies_clone = bodies.clone();
-        for i in 0..rows_n {
-            for j in 0..columns_n {
-                for lhs_body_id in &cells[i][j].bodies {
-                    let lhs_body = bodies.get_mut(lhs_body_id).unwrap();
-                    for (m, row) in cells.iter().enumerate() {
-                        for (n, cell) in row.iter().enumerate() {
-                            if (i.saturating_sub(1)..=(i + 1).min(rows_n - 1)).contains(&m)
-                                && (j.saturating_sub(1)..=(j + 1).min(columns_n - 1)).contains(&n)
-                            {
-                                for rhs_body_id in &cell.bodies {
-                                    if lhs_body_id != rhs_body_id {
-                                        let rhs_body = bodies_clone.get(rhs_body_id).unwrap();
-
-                                        lhs_body.adjust_speed(rhs_body.pos, rhs_body.mass)
-                                    }
-                                }
-                            } else {
-                                lhs_body.adjust_speed(cell.pos, cell.total_mass)
+        // Far field: every cell gets the field of the cells outside its 3x3 stencil,
+        // expanded around its centre of mass, so bodies only have to evaluate it.
+        // The cells are merged into ever coarser levels, and from every level a cell
+        // only takes those within the stencil of its ancestor's parent but outside
+        // that of its ancestor, so it takes a few per level instead of every cell.
+        let mut levels = vec![Level {
+            counts,
+            strides,
+            cells,
+        }];
+        while levels.last().unwrap().counts.iter().any(|count| *count > 3) {
+            let coarser = levels.last().unwrap().coarsen();
+            levels.push(coarser);
+        }
+
+        let mut expansions = vec![None; cells_n];
+        for (cell, expansion) in levels[0].cells.iter().zip(expansions.iter_mut()) {
+            if cell.total_mass == 0.0 {
+                continue;
+            }
+
+            let mut cell_expansion = LocalExpansion::new(cell.pos);
+            let mut indices = cell.indices;
+            for (depth, level) in levels.iter().enumerate() {
+                let mut add = |rhs_cell: &Cell| {
+                    if rhs_cell.total_mass != 0.0 && !is_adjacent(indices, rhs_cell.indices) {
+                        for monopole in rhs_cell.sources.get_monopoles() {
+                            cell_expansion.add(monopole.pos, monopole.source, force_law);
+                        }
+                    }
+                };
+
+                match levels.get(depth + 1) {
+                    // At the top, there are few enough cells to take them all
+                    None => level.cells.iter().for_each(add),
+                    Some(parent) => {
+                        for neighbor in get_stencil(indices.map(|index| index / 2), parent.counts) {
+                            for child in get_children(neighbor, level.counts) {
+                                add(&level.cells[level.get_flat_index(child)]);
                             }
                         }
                     }
                 }
+
+                indices = indices.map(|index| index / 2);
             }
+            *expansion = Some(cell_expansion);
         }
+        let cells = levels.swap_remove(0).cells;
 
-        let end = start.elapsed();
+        // Near field: direct interactions within the 3x3 stencil.
+        let bodies_clone = bodies.clone();
+        for (cell, expansion) in cells.iter().zip(&expansions) {
+            for lhs_body_id in &cell.bodies {
+                let lhs_body = bodies.get_mut(lhs_body_id).unwrap();
 
-        if Self::DRAW {
-            let border = BORDER_THICKNESS / zoom.zoom;
+                for neighbor in get_stencil(cell.indices, counts) {
+                    for rhs_body_id in &cells[get_flat_index(neighbor)].bodies {
+                        if lhs_body_id != rhs_body_id {
+                            let rhs_body = bodies_clone.get(rhs_body_id).unwrap();
 
-            for i in 0..=rows_n {
-                draw_line(
-                    rectangle.top_left.re() as f32,
-                    rectangle.top_left.im() as f32 + i as f32 * cell_height as f32,
-                    rectangle.bottom_right.re() as f32,
-                    rectangle.top_left.im() as f32 + i as f32 * cell_height as f32,
-                    border,
-                    BORDER_COLOR,
-                );
+                            lhs_body.adjust_speed(
+                                rhs_body.pos,
+                                force_law.get_source(rhs_body),
+                                force_law,
+                            )
+                        }
+                    }
+                }
+
+                if let Some(expansion) = expansion {
+                    lhs_body.accelerate(
+                        force_law.get_response(lhs_body) * expansion.evaluate(lhs_body.pos),
+                    );
+                }
             }
+        }
+
+        let end = start.elapsed();
 
-            for j in 0..=columns_n {
-                draw_line(
-                    rectangle.top_left.re() as f32 + j as f32 * cell_width as f32,
-                    rectangle.top_left.im() as f32,
-                    rectangle.top_left.re() as f32 + j as f32 * cell_width as f32,
-                    rectangle.bottom_right.im() as f32,
+        if *DRAW.read().unwrap() {
+            let border = BORDER_THICKNESS * camera.get_scale();
+
+            for cell in &cells {
+                let corner = from_fn(|k| edges[k][cell.indices[k]]);
+                let size = from_fn(|k| edges[k][cell.indices[k] + 1] - corner[k]);
+
+                draw_box(
+                    Vector::from_components(corner),
+                    Vector::from_components(size),
                     border,
                     BORDER_COLOR,
                 );
@@ -132,3 +436,50 @@ impl Grid {
         end
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direct::Direct;
+    use ::rand::{Rng, SeedableRng, rngs::StdRng};
+
+    /// Bodies at rest, spread uniformly over a square or a cube.
+    fn get_bodies(bodies_n: usize) -> HashMap<BodyID, Body> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..bodies_n)
+            .map(|body_id| {
+                let body = Body {
+                    pos: Vector::from_components(from_fn(|_| rng.random_range(0.0..1000.0))),
+                    speed: Vector::ZERO,
+                    mass: 1.0,
+                    radius: 0.0,
+                    density: 1.0,
+                    charge: 1.0,
+                    species: 0,
+                };
+
+                (body_id as BodyID, body)
+            })
+            .collect()
+    }
+
+    /// The far field of the coarser levels agrees with the direct sum.
+    #[test]
+    fn far_field() {
+        let bodies = get_bodies(2000);
+        let mut direct = bodies.clone();
+        Direct::handle(&mut direct, ForceLaw::Newtonian);
+        let mut grid = bodies;
+        Grid::handle(&mut grid, ForceLaw::Newtonian, &Camera::default());
+
+        let error = grid
+            .iter()
+            .map(|(body_id, body)| {
+                let expected = direct[body_id].speed;
+                (body.speed - expected).length() / expected.length()
+            })
+            .sum::<f64>()
+            / grid.len() as f64;
+        assert!(error < 0.03, "{} mean relative error", error);
+    }
+}
@@ -0,0 +1,361 @@
+use crate::{
+    Camera, barnes_hut,
+    body::DT,
+    cli::{BenchArgs, CliError, RunArgs},
+    generate_bodies, get_presets, grid, import_bodies, multigrid, new_simulations,
+    output::Trajectory,
+    scenario::Scenario,
+    snapshot::{self, Snapshot},
+    solver::{Simulation, Solver},
+    units::{Quantity, SCALES, Units},
+    vector::Vector,
+};
+use ::rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// The simulations of `scenario` starting from the same bodies, or carrying on
+/// from the snapshot at `resume`, with the units they are reported in, the seed
+/// of the initial conditions and the generator it was drawn from.
+fn get_simulations(
+    scenario: &Scenario,
+    resume: Option<&Path>,
+) -> Result<(Vec<Simulation>, Units, u64, ChaCha12Rng), CliError> {
+    // There is nothing to draw on
+    *barnes_hut::DRAW.write().unwrap() = false;
+    *multigrid::DRAW.write().unwrap() = false;
+    *grid::DRAW.write().unwrap() = false;
+
+    if let Some(path) = resume {
+        let snapshot =
+            Snapshot::load(path).map_err(|error| CliError::Snapshot(path.to_path_buf(), error))?;
+        snapshot.apply();
+        println!(
+            "Carrying on from {} at step {} (seed {})",
+            path.display(),
+            snapshot
+                .simulations
+                .first()
+                .map_or(0, |simulation| simulation.steps),
+            snapshot.seed
+        );
+
+        return Ok((
+            snapshot.simulations,
+            snapshot.units,
+            snapshot.seed,
+            snapshot.rng,
+        ));
+    }
+
+    let generation = scenario
+        .initial_conditions
+        .to_simulation(&scenario.get_scales());
+    let mut rng = ChaCha12Rng::from_os_rng();
+    let seed = generation.seed.unwrap_or_else(|| rng.random());
+
+    if let Some(import) = &generation.import {
+        let bodies = import_bodies(import, &scenario.directory, generation.density)?;
+        println!(
+            "{} bodies from {}",
+            bodies.len(),
+            scenario.directory.join(&import.path).display()
+        );
+
+        return Ok((
+            new_simulations(scenario, &bodies),
+            scenario.units,
+            seed,
+            rng,
+        ));
+    }
+
+    let (initial_conditions_presets, mass_spectra) = get_presets(&generation, Vector::ZERO);
+    println!(
+        "{} of {} bodies ({}), seed {}",
+        initial_conditions_presets[0].name(),
+        generation.bodies_n,
+        mass_spectra[0].name(),
+        seed
+    );
+    let bodies = generate_bodies(
+        initial_conditions_presets[0],
+        mass_spectra[0],
+        &generation,
+        seed,
+    )
+    .map_err(CliError::Generation)?;
+
+    Ok((
+        new_simulations(scenario, &bodies),
+        scenario.units,
+        seed,
+        rng,
+    ))
+}
+
+/// A CSV file and where it is.
+type Output = (PathBuf, BufWriter<File>);
+
+fn write_line(output: &mut Option<Output>, line: &str) -> Result<(), CliError> {
+    match output {
+        Some((path, writer)) => {
+            writeln!(writer, "{}", line).map_err(|error| CliError::Io(path.clone(), error))
+        }
+        None => Ok(()),
+    }
+}
+
+pub fn run(args: &RunArgs) -> Result<(), CliError> {
+    let scenario = args.setup.load()?;
+    scenario.apply();
+    let (mut simulations, units, seed, rng) = get_simulations(&scenario, args.resume.as_deref())?;
+    let camera = Camera::default();
+    let energy_diagnostics = scenario.outputs.energy_diagnostics;
+    // Those of the snapshot when resuming
+    let scales = *SCALES.read().unwrap();
+
+    let mut output = match &args.output {
+        Some(path) => {
+            let file = File::create(path).map_err(|error| CliError::Io(path.clone(), error))?;
+            Some((path.clone(), BufWriter::new(file)))
+        }
+        None => None,
+    };
+    write_line(
+        &mut output,
+        "step,time,solver,bodies_n,duration_per_body_ns,kinetic,potential,drift",
+    )?;
+
+    let mut trajectories = Vec::with_capacity(scenario.outputs.trajectories.len());
+    for trajectory in &scenario.outputs.trajectories {
+        trajectories.push(
+            Trajectory::create(trajectory, &scenario.directory)
+                .map_err(|error| CliError::Io(scenario.directory.join(&trajectory.path), error))?,
+        );
+    }
+    let record = |trajectories: &mut [Trajectory], simulations: &[Simulation]| {
+        trajectories.iter_mut().try_for_each(|trajectory| {
+            trajectory
+                .record(simulations)
+                .map_err(|error| CliError::Io(trajectory.path.clone(), error))
+        })
+    };
+
+    for simulation in &mut simulations {
+        simulation.measure_energy();
+    }
+    record(&mut trajectories, &simulations)?;
+
+    for step in 1..=args.steps {
+        for simulation in &mut simulations {
+            simulation.step(DT.get(), false, &camera);
+
+            // Without diagnostics, the energy is only measured once more at the end
+            if energy_diagnostics || step == args.steps {
+                simulation.measure_energy();
+            }
+
+            let energy = match (simulation.energy, simulation.get_energy_drift()) {
+                (Some(energy), Some(drift)) if energy_diagnostics => format!(
+                    "{:e},{:e},{:e}",
+                    scales.in_scenario(Quantity::Energy, energy.kinetic),
+                    scales.in_scenario(Quantity::Energy, energy.potential),
+                    drift
+                ),
+                _ => ",,".to_owned(),
+            };
+            write_line(
+                &mut output,
+                &format!(
+                    "{},{},{},{},{},{}",
+                    simulation.steps,
+                    scales.in_scenario(Quantity::Time, simulation.time),
+                    simulation.solver.name(),
+                    simulation.bodies.len(),
+                    simulation.duration,
+                    energy
+                ),
+            )?;
+        }
+
+        record(&mut trajectories, &simulations)?;
+
+        if let Some(path) = &args.snapshot
+            && (step == args.steps
+                || args
+                    .checkpoint_interval
+                    .is_some_and(|interval| step % interval == 0))
+        {
+            snapshot::save(path, &simulations, &units, seed, &rng)
+                .map_err(|error| CliError::Snapshot(path.clone(), error))?;
+        }
+    }
+
+    if let Some((path, writer)) = &mut output {
+        writer
+            .flush()
+            .map_err(|error| CliError::Io(path.clone(), error))?;
+    }
+
+    println!("After {} steps:", args.steps);
+    for simulation in &simulations {
+        println!(
+            "{}: {} bodies, energy drift {:+.2e}",
+            simulation.solver.name(),
+            simulation.bodies.len(),
+            simulation.get_energy_drift().unwrap_or(f64::NAN)
+        );
+    }
+
+    Ok(())
+}
+
+pub fn bench(args: &BenchArgs) -> Result<(), CliError> {
+    let scenario = args.setup.load()?;
+    scenario.apply();
+    let (simulations, _, _, _) = get_simulations(&scenario, None)?;
+    let camera = Camera::default();
+
+    // One solver at a time, so that they don't share the caches
+    for mut simulation in simulations {
+        let mut durations = Vec::with_capacity(args.steps);
+        let start = Instant::now();
+        for _ in 0..args.steps {
+            simulation.step(DT.get(), false, &camera);
+            durations.push(simulation.duration);
+        }
+        let elapsed = start.elapsed();
+
+        durations.sort_by(f64::total_cmp);
+        println!(
+            "{}: {:.0} ns per body per step (median), {:.0} (min), {:.2?} per step overall",
+            simulation.solver.name(),
+            durations.get(durations.len() / 2).unwrap_or(&f64::NAN),
+            durations.first().unwrap_or(&f64::NAN),
+            elapsed / args.steps.max(1) as u32
+        );
+        if let Solver::BarnesHut(barnes_hut) = &simulation.solver
+            && barnes_hut.incremental
+        {
+            println!(
+                "{}: the tree was rebuilt in {} of {} steps",
+                simulation.solver.name(),
+                barnes_hut.rebuilds_n,
+                args.steps
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::{ConvertArgs, Setup},
+        convert,
+    };
+    use std::{fs, num::NonZero};
+
+    fn get_setup(scenario: PathBuf) -> Setup {
+        Setup {
+            scenario: Some(scenario),
+            seed: None,
+            bodies: None,
+            import: None,
+            solvers: None,
+            trajectory: Vec::new(),
+            trajectory_interval: NonZero::new(1).unwrap(),
+        }
+    }
+
+    /// A directory of its own, as the tests run side by side, relative like on the
+    /// command line, which is where the paths of the scenario could double.
+    fn get_directory(name: &str) -> PathBuf {
+        let directory =
+            PathBuf::from("target").join(format!("gravity_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Converts the scenario `text` in `directory` and runs the result for `steps`,
+    /// returning what it was converted to.
+    fn convert_then_run(directory: &Path, text: &str, steps: usize) -> String {
+        let scenario = directory.join("scenario.toml");
+        fs::write(&scenario, text).unwrap();
+
+        let converted = directory.join("converted.toml");
+        convert(&ConvertArgs {
+            setup: get_setup(scenario),
+            output: Some(converted.clone()),
+        })
+        .unwrap();
+
+        run(&RunArgs {
+            setup: get_setup(converted.clone()),
+            steps,
+            output: None,
+            resume: None,
+            snapshot: None,
+            checkpoint_interval: None,
+        })
+        .unwrap();
+
+        fs::read_to_string(&converted).unwrap()
+    }
+
+    #[test]
+    fn converted_trajectory() {
+        let directory = get_directory("converted_trajectory");
+        let converted = convert_then_run(
+            &directory,
+            "[initial_conditions]\n\
+             bodies_n = 5\n\
+             \n\
+             [[solvers]]\n\
+             kind = \"direct\"\n\
+             \n\
+             [[outputs.trajectories]]\n\
+             format = \"csv\"\n\
+             path = \"trajectory.csv\"\n\
+             interval = 1\n",
+            2,
+        );
+        assert!(converted.contains("path = \"trajectory.csv\""));
+
+        // The header, then the bodies at steps 0, 1 and 2
+        let trajectory = fs::read_to_string(directory.join("trajectory.csv")).unwrap();
+        assert_eq!(trajectory.lines().count(), 1 + 3 * 5);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn converted_import() {
+        let directory = get_directory("converted_import");
+        fs::write(
+            directory.join("bodies.csv"),
+            "x,y,vx,vy,mass\n-10,0,0,-1,1\n10,0,0,1,1\n",
+        )
+        .unwrap();
+        let converted = convert_then_run(
+            &directory,
+            "[initial_conditions]\n\
+             import = { path = \"bodies.csv\" }\n\
+             \n\
+             [[solvers]]\n\
+             kind = \"direct\"\n",
+            1,
+        );
+        assert!(converted.contains("path = \"bodies.csv\""));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
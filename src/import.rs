@@ -0,0 +1,252 @@
+use crate::{
+    body::{Body, BodyID, INITIAL_CHARGE, new_body_id, reserve_body_id},
+    drag::{SPECIES, SpeciesID},
+    vector::{DIMENSIONS, Vector, VectorExt},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+/// Bodies read from a file instead of drawn by a generator, such as a catalogue
+/// or the output of another code.
+///
+/// A CSV file has a header naming the columns, a JSON file is an array of objects
+/// with the same keys: `x`, `y`, `z`, `vx`, `vy`, `vz`, `mass`, `radius`, `id`,
+/// `species` and `charge`. Only `x`, `y`, `vx`, `vy` and `mass` are required, the
+/// third components being dropped in 2D, and any other column is ignored.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Import {
+    /// A `.json` file, or else CSV, relative to the scenario.
+    pub path: PathBuf,
+    /// What the positions and radii of the file are multiplied by.
+    #[serde(default = "get_unit_scale")]
+    pub length: f64,
+    /// What the speeds of the file are multiplied by.
+    #[serde(default = "get_unit_scale")]
+    pub speed: f64,
+    /// What the masses of the file are multiplied by.
+    #[serde(default = "get_unit_scale")]
+    pub mass: f64,
+    /// Whether to move the bodies to their centre of mass and cancel their momentum,
+    /// like those of the generators, instead of keeping them as the file has them.
+    #[serde(default)]
+    pub recenter: bool,
+}
+
+fn get_unit_scale() -> f64 {
+    1.0
+}
+
+/// A body as written in the file.
+#[derive(Deserialize)]
+struct Record {
+    x: f64,
+    y: f64,
+    z: Option<f64>,
+    vx: f64,
+    vy: f64,
+    vz: Option<f64>,
+    mass: f64,
+    /// From the mass and the density if left out.
+    radius: Option<f64>,
+    /// A new one if left out.
+    id: Option<BodyID>,
+    /// The first species if left out.
+    species: Option<SpeciesName>,
+    charge: Option<f64>,
+}
+
+/// A species by its name, whatever the case, or by its index.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpeciesName {
+    Index(SpeciesID),
+    Name(String),
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    Csv(csv::Error),
+    Json(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "cannot read the bodies: {}", error),
+            Self::Csv(error) => write!(f, "cannot parse the bodies: {}", error),
+            Self::Json(error) => write!(f, "cannot parse the bodies: {}", error),
+            Self::Invalid(message) => write!(f, "invalid bodies: {}", message),
+        }
+    }
+}
+
+impl Import {
+    /// A file taken as is.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            length: 1.0,
+            speed: 1.0,
+            mass: 1.0,
+            recenter: false,
+        }
+    }
+
+    /// The bodies scaled, and moved to their centre of mass if `recenter` says so,
+    /// the ones without a radius getting `density`. The path is relative to `directory`.
+    pub fn load(
+        &self,
+        directory: &Path,
+        density: f64,
+    ) -> Result<HashMap<BodyID, Body>, ImportError> {
+        let records = read_records(&directory.join(&self.path))?;
+        if records.is_empty() {
+            return Err(ImportError::Invalid("the file has no bodies".to_owned()));
+        }
+
+        // The IDs of the file first, so that the new ones don't clash with them
+        let mut body_ids = HashSet::new();
+        for (index, record) in records.iter().enumerate() {
+            if let Some(body_id) = record.id {
+                if !body_ids.insert(body_id) {
+                    return Err(invalid(index, format!("the ID {} is taken", body_id)));
+                }
+                if !reserve_body_id(body_id) {
+                    return Err(invalid(
+                        index,
+                        format!("the ID {} leaves none for new bodies", body_id),
+                    ));
+                }
+            }
+        }
+
+        let species = SPECIES.read().unwrap();
+        let mut bodies = HashMap::with_capacity(records.len());
+        for (index, record) in records.into_iter().enumerate() {
+            let mut pos = [record.x, record.y, record.z.unwrap_or(0.0)];
+            let mut speed = [record.vx, record.vy, record.vz.unwrap_or(0.0)];
+            for component in &mut pos {
+                *component *= self.length;
+            }
+            for component in &mut speed {
+                *component *= self.speed;
+            }
+            if pos
+                .into_iter()
+                .chain(speed)
+                .any(|component| !component.is_finite())
+            {
+                return Err(invalid(
+                    index,
+                    "the position and speed must be finite".to_owned(),
+                ));
+            }
+
+            let mass = record.mass * self.mass;
+            if !(mass > 0.0 && mass.is_finite()) {
+                return Err(invalid(
+                    index,
+                    format!("the mass must be positive, not {}", mass),
+                ));
+            }
+
+            let (radius, density) = match record.radius {
+                Some(radius) => {
+                    let radius = radius * self.length;
+                    if !(radius > 0.0 && radius.is_finite()) {
+                        return Err(invalid(
+                            index,
+                            format!("the radius must be positive, not {}", radius),
+                        ));
+                    }
+                    (radius, mass / radius.powi(3))
+                }
+                None => (Body::get_radius(mass, density), density),
+            };
+
+            let species_id = match record.species {
+                Some(SpeciesName::Index(species_id)) if species_id < species.len() => species_id,
+                Some(SpeciesName::Index(species_id)) => {
+                    return Err(invalid(
+                        index,
+                        format!("there is no species at index {}", species_id),
+                    ));
+                }
+                Some(SpeciesName::Name(name)) => species
+                    .iter()
+                    .position(|species| species.name.eq_ignore_ascii_case(&name))
+                    .ok_or_else(|| {
+                        invalid(index, format!("there is no species called `{}`", name))
+                    })?,
+                None => 0,
+            };
+
+            let charge = record.charge.unwrap_or(INITIAL_CHARGE);
+            if !charge.is_finite() {
+                return Err(invalid(index, "the charge must be finite".to_owned()));
+            }
+
+            bodies.insert(
+                record.id.unwrap_or_else(new_body_id),
+                Body {
+                    pos: Vector::from_components(pos[..DIMENSIONS].try_into().unwrap()),
+                    speed: Vector::from_components(speed[..DIMENSIONS].try_into().unwrap()),
+                    mass,
+                    radius,
+                    density,
+                    charge,
+                    species: species_id,
+                },
+            );
+        }
+        if !self.recenter {
+            return Ok(bodies);
+        }
+
+        Body::adjust_momentum(&mut bodies);
+        // Where the presets are laid out, whatever the coordinates of the file
+        let total_mass = bodies.values().map(|body| body.mass).sum::<f64>();
+        let center_of_mass = bodies
+            .values()
+            .map(|body| body.mass * body.pos)
+            .sum::<Vector>()
+            / total_mass;
+        for body in bodies.values_mut() {
+            body.pos -= center_of_mass;
+        }
+
+        Ok(bodies)
+    }
+}
+
+fn read_records(path: &Path) -> Result<Vec<Record>, ImportError> {
+    let reader = BufReader::new(File::open(path).map_err(ImportError::Io)?);
+
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        serde_json::from_reader(reader).map_err(ImportError::Json)
+    } else {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(ImportError::Csv)
+    }
+}
+
+/// What is wrong with the body at `index`, counted from one as in the file.
+fn invalid(index: usize, message: String) -> ImportError {
+    ImportError::Invalid(format!("body {}: {}", index + 1, message))
+}
@@ -0,0 +1,1267 @@
+use crate::{
+    barnes_hut::{Mac, Tree},
+    body::{Body, BodyID, DT, G, INITIAL_CHARGE, new_body_id},
+    external_field::{EXTERNAL_FIELDS, get_external_acceleration},
+    force_law::FORCE_LAW,
+    units::{Quantity, Scales},
+    vector::{DIMENSIONS, Vector, VectorExt},
+};
+use ::rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, f64::consts::PI, fmt};
+
+/// How far out the Plummer and Hernquist profiles are truncated, in scale radii.
+const TRUNCATION: f64 = 20.0;
+/// The masses of the planets of `SolarSystem` in Earth masses, from the innermost out.
+const PLANET_MASSES: [f64; 8] = [0.055, 0.815, 1.0, 0.107, 317.8, 95.2, 14.5, 17.1];
+/// The asteroid belt of `SolarSystem` lies between these powers of the orbit ratio,
+/// that is between the fourth and the fifth planet.
+const BELT: [f64; 2] = [3.25, 3.75];
+/// The step of the integration of King's Poisson equation, in King radii.
+const KING_STEP: f64 = 1e-3;
+/// How far out `RotatingDisk` is truncated, in scale lengths.
+const DISK_TRUNCATION: f64 = 5.0;
+/// The scale height of `RotatingDisk` in 3D, in scale lengths.
+const DISK_THICKNESS: f64 = 0.1;
+/// The number of rings `RotatingDisk` averages the rotation curve over.
+const RINGS_N: usize = 32;
+/// The theta `RotatingDisk` finds the accelerations with, whatever the solvers use.
+const GENERATION_THETA: f64 = 0.5;
+/// How many positions are drawn for a body before the region is taken to be too
+/// crowded for it.
+const MAX_PLACEMENT_ATTEMPTS: usize = 1000;
+
+/// The bodies don't fit into the region of the generator without overlapping.
+#[derive(Debug)]
+pub struct CrowdedError {
+    pub name: &'static str,
+    pub bodies_n: usize,
+}
+
+impl fmt::Display for CrowdedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the {} generator cannot fit {} bodies without overlapping, \
+             try fewer of them or a larger region",
+            self.name, self.bodies_n
+        )
+    }
+}
+
+/// A recipe for the bodies a simulation starts from. The masses of the bodies
+/// are drawn from a `MassSpectrum` unless said otherwise, and their charges are
+/// of a random sign. Scenario files give them without `center`, which is
+/// then filled in with `set_center`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum InitialConditions {
+    /// Uniformly filling an ellipse with semi-axes `radius` along x and `aspect * radius`
+    /// along y, thickened in 3D into an ellipsoid as deep as it is tall,
+    /// with speeds of `speed` in random directions.
+    UniformDisk {
+        #[serde(skip)]
+        center: Vector,
+        radius: f64,
+        aspect: f64,
+        speed: f64,
+    },
+    /// The density `(1 + r^2 / a^2)^(-5/2)` with `a` being `radius`, with isotropic
+    /// speeds from its distribution function.
+    Plummer {
+        #[serde(skip)]
+        center: Vector,
+        radius: f64,
+    },
+    /// The density `1 / (r (r + a)^3)` with `a` being `radius`, with isotropic
+    /// Gaussian speeds of the dispersion from the Jeans equation.
+    Hernquist {
+        #[serde(skip)]
+        center: Vector,
+        radius: f64,
+    },
+    /// A lowered isothermal sphere with the dimensionless central potential
+    /// `concentration` (W0) and the King radius `radius`.
+    King {
+        #[serde(skip)]
+        center: Vector,
+        radius: f64,
+        concentration: f64,
+    },
+    /// A body of `central_mass` with the others on circular orbits around it, with a
+    /// uniform surface density between `inner_radius` and `outer_radius`.
+    KeplerianDisk {
+        #[serde(skip)]
+        center: Vector,
+        central_mass: f64,
+        inner_radius: f64,
+        outer_radius: f64,
+    },
+    /// An exponential disk of `scale_length` in the xy plane, in balance with its own
+    /// gravity and the external fields: the circular speeds come from the accelerations
+    /// Barnes-Hut finds, and the bodies are given `dispersion` around them,
+    /// their mean rotation lagging to make up for it.
+    RotatingDisk {
+        #[serde(skip)]
+        center: Vector,
+        scale_length: f64,
+        dispersion: Dispersion,
+    },
+    /// A square or cubic grid of bodies `spacing` apart, at rest, which must be
+    /// more than the largest body across.
+    Lattice {
+        #[serde(skip)]
+        center: Vector,
+        spacing: f64,
+    },
+    /// Two Keplerian disks of `radius`, `separation` apart along x and offset by
+    /// `impact_parameter` along y, falling towards each other at `approach_speed`.
+    TwoGalaxies {
+        #[serde(skip)]
+        center: Vector,
+        central_mass: f64,
+        radius: f64,
+        separation: f64,
+        impact_parameter: f64,
+        approach_speed: f64,
+    },
+    /// A star of `star_mass` with up to eight planets of Solar System mass ratios,
+    /// `earth_mass` being the mass of the third one, on circular orbits growing by
+    /// `orbit_ratio` from `innermost_orbit`. The other bodies form an asteroid belt
+    /// between the fourth and the fifth orbit.
+    SolarSystem {
+        #[serde(skip)]
+        center: Vector,
+        star_mass: f64,
+        earth_mass: f64,
+        planets_n: usize,
+        innermost_orbit: f64,
+        orbit_ratio: f64,
+    },
+}
+
+impl InitialConditions {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UniformDisk { .. } => "Uniform disk",
+            Self::Plummer { .. } => "Plummer sphere",
+            Self::Hernquist { .. } => "Hernquist sphere",
+            Self::King { .. } => "King sphere",
+            Self::KeplerianDisk { .. } => "Keplerian disk",
+            Self::RotatingDisk { dispersion, .. } => match dispersion {
+                Dispersion::ToomreQ(_) => "Rotating disk (Toomre Q)",
+                Dispersion::Constant(_) => "Rotating disk (constant dispersion)",
+            },
+            Self::Lattice { .. } => "Lattice",
+            Self::TwoGalaxies { .. } => "Two galaxies",
+            Self::SolarSystem { .. } => "Solar System",
+        }
+    }
+
+    pub fn set_center(&mut self, new_center: Vector) {
+        match self {
+            Self::UniformDisk { center, .. }
+            | Self::Plummer { center, .. }
+            | Self::Hernquist { center, .. }
+            | Self::King { center, .. }
+            | Self::KeplerianDisk { center, .. }
+            | Self::RotatingDisk { center, .. }
+            | Self::Lattice { center, .. }
+            | Self::TwoGalaxies { center, .. }
+            | Self::SolarSystem { center, .. } => *center = new_center,
+        }
+    }
+
+    /// The same recipe with the numbers of a scenario's units in the simulation's.
+    pub fn to_simulation(self, scales: &Scales) -> Self {
+        let length = |length| scales.in_simulation(Quantity::Length, length);
+        let mass = |mass| scales.in_simulation(Quantity::Mass, mass);
+        let speed = |speed| scales.in_simulation(Quantity::Speed, speed);
+
+        match self {
+            Self::UniformDisk {
+                center,
+                radius,
+                aspect,
+                speed: abs_speed,
+            } => Self::UniformDisk {
+                center,
+                radius: length(radius),
+                aspect,
+                speed: speed(abs_speed),
+            },
+            Self::Plummer { center, radius } => Self::Plummer {
+                center,
+                radius: length(radius),
+            },
+            Self::Hernquist { center, radius } => Self::Hernquist {
+                center,
+                radius: length(radius),
+            },
+            Self::King {
+                center,
+                radius,
+                concentration,
+            } => Self::King {
+                center,
+                radius: length(radius),
+                concentration,
+            },
+            Self::KeplerianDisk {
+                center,
+                central_mass,
+                inner_radius,
+                outer_radius,
+            } => Self::KeplerianDisk {
+                center,
+                central_mass: mass(central_mass),
+                inner_radius: length(inner_radius),
+                outer_radius: length(outer_radius),
+            },
+            Self::RotatingDisk {
+                center,
+                scale_length,
+                dispersion,
+            } => Self::RotatingDisk {
+                center,
+                scale_length: length(scale_length),
+                dispersion: match dispersion {
+                    Dispersion::ToomreQ(q) => Dispersion::ToomreQ(q),
+                    Dispersion::Constant(dispersion) => Dispersion::Constant(speed(dispersion)),
+                },
+            },
+            Self::Lattice { center, spacing } => Self::Lattice {
+                center,
+                spacing: length(spacing),
+            },
+            Self::TwoGalaxies {
+                center,
+                central_mass,
+                radius,
+                separation,
+                impact_parameter,
+                approach_speed,
+            } => Self::TwoGalaxies {
+                center,
+                central_mass: mass(central_mass),
+                radius: length(radius),
+                separation: length(separation),
+                impact_parameter: length(impact_parameter),
+                approach_speed: speed(approach_speed),
+            },
+            Self::SolarSystem {
+                center,
+                star_mass,
+                earth_mass,
+                planets_n,
+                innermost_orbit,
+                orbit_ratio,
+            } => Self::SolarSystem {
+                center,
+                star_mass: mass(star_mass),
+                earth_mass: mass(earth_mass),
+                planets_n,
+                innermost_orbit: length(innermost_orbit),
+                orbit_ratio,
+            },
+        }
+    }
+
+    /// `bodies_n` bodies of `density`, the same for the same `seed`.
+    pub fn generate(
+        &self,
+        bodies_n: usize,
+        mass_spectrum: MassSpectrum,
+        density: f64,
+        seed: u64,
+    ) -> Result<HashMap<BodyID, Body>, CrowdedError> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let masses = (0..bodies_n)
+            .map(|_| mass_spectrum.sample(&mut rng))
+            .collect::<Vec<_>>();
+        let total_mass = masses.iter().sum::<f64>();
+        let max_mass = masses.iter().copied().fold(0.0, f64::max);
+        let mut placement = Placement::new(Body::get_radius(max_mass, density), density);
+
+        let bodies = match *self {
+            Self::UniformDisk {
+                center,
+                radius,
+                aspect,
+                speed,
+            } => masses
+                .iter()
+                .map(|&mass| {
+                    let pos = placement.place(mass, || {
+                        let distance = radius * rng.random_range(0.0..1.0f64).sqrt();
+                        let angle = rng.random_range(0.0..2.0 * PI);
+
+                        let mut offset = [0.0; DIMENSIONS];
+                        offset[0] = distance * angle.cos();
+                        offset[1] = aspect * distance * angle.sin();
+                        for component in offset.iter_mut().skip(2) {
+                            *component = aspect
+                                * radius
+                                * (1.0 - (distance / radius).powi(2)).sqrt()
+                                * rng.random_range(-1.0..1.0);
+                        }
+
+                        center + Vector::from_components(offset)
+                    });
+
+                    let speed = speed * Vector::random_direction(&mut rng);
+                    new_body(pos, speed, mass, density, &mut rng)
+                })
+                .collect(),
+            Self::Plummer { center, radius } => {
+                let max_fraction = (1.0 + TRUNCATION.powi(-2)).powf(-1.5);
+
+                masses
+                    .iter()
+                    .map(|&mass| {
+                        let mut distance = 0.0;
+                        let pos = placement.place(mass, || {
+                            let fraction = rng.random_range(0.0..max_fraction);
+                            distance = radius / (fraction.powf(-2.0 / 3.0) - 1.0).sqrt();
+                            center + distance * Vector::random_direction(&mut rng)
+                        });
+
+                        // Aarseth, Hénon and Wielen (1974): `q` is distributed as
+                        // `q^2 (1 - q^2)^(7/2)`, below 0.1
+                        let q = loop {
+                            let q = rng.random_range(0.0..1.0f64);
+                            if rng.random_range(0.0..0.1) < q.powi(2) * (1.0 - q.powi(2)).powf(3.5)
+                            {
+                                break q;
+                            }
+                        };
+                        let escape_speed = (2.0 * G.get() * total_mass
+                            / (distance.powi(2) + radius.powi(2)).sqrt())
+                        .sqrt();
+
+                        let speed = q * escape_speed * Vector::random_direction(&mut rng);
+                        new_body(pos, speed, mass, density, &mut rng)
+                    })
+                    .collect()
+            }
+            Self::Hernquist { center, radius } => {
+                let max_fraction = (TRUNCATION / (TRUNCATION + 1.0)).powi(2);
+                // The mass of the untruncated profile
+                let profile_mass = total_mass / max_fraction;
+
+                masses
+                    .iter()
+                    .map(|&mass| {
+                        let mut distance = 0.0;
+                        let pos = placement.place(mass, || {
+                            let root = rng.random_range(0.0..max_fraction).sqrt();
+                            distance = radius * root / (1.0 - root);
+                            center + distance * Vector::random_direction(&mut rng)
+                        });
+
+                        // Hernquist (1990), equation 10
+                        let x = distance / radius;
+                        let dispersion = (G.get() * profile_mass / (12.0 * radius)
+                            * (12.0 * x * (1.0 + x).powi(3) * ((1.0 + x) / x).ln()
+                                - x / (1.0 + x)
+                                    * (25.0 + 52.0 * x + 42.0 * x.powi(2) + 12.0 * x.powi(3))))
+                        .max(0.0)
+                        .sqrt();
+
+                        let speed =
+                            Vector::from_components(std::array::from_fn(|_| get_normal(&mut rng)));
+                        new_body(pos, dispersion * speed, mass, density, &mut rng)
+                    })
+                    .collect()
+            }
+            Self::King {
+                center,
+                radius,
+                concentration,
+            } => {
+                let profile = KingProfile::new(concentration);
+                // The one-dimensional velocity dispersion of the isothermal sphere
+                // the model is lowered from, from `r0^2 = 9 sigma^2 / (4 pi G rho0)`
+                let dispersion =
+                    (G.get() * total_mass / (9.0 * radius * profile.get_total_mass())).sqrt();
+
+                masses
+                    .iter()
+                    .map(|&mass| {
+                        let mut potential = 0.0;
+                        let pos = placement.place(mass, || {
+                            let (distance, w) = profile.sample(&mut rng);
+                            potential = w;
+                            center + radius * distance * Vector::random_direction(&mut rng)
+                        });
+
+                        let speed = dispersion
+                            * KingProfile::sample_speed(potential, &mut rng)
+                            * Vector::random_direction(&mut rng);
+                        new_body(pos, speed, mass, density, &mut rng)
+                    })
+                    .collect()
+            }
+            Self::KeplerianDisk {
+                center,
+                central_mass,
+                inner_radius,
+                outer_radius,
+            } => get_keplerian_disk(
+                &masses,
+                center,
+                central_mass,
+                [inner_radius, outer_radius],
+                &mut placement,
+                &mut rng,
+            ),
+            Self::RotatingDisk {
+                center,
+                scale_length,
+                dispersion,
+            } => get_rotating_disk(
+                &masses,
+                center,
+                scale_length,
+                dispersion,
+                &mut placement,
+                &mut rng,
+            ),
+            Self::Lattice { center, spacing } => {
+                // The positions are fixed, so the bodies fit or all touch their neighbours
+                if spacing <= 2.0 * Body::get_radius(max_mass, density) {
+                    return Err(CrowdedError {
+                        name: self.name(),
+                        bodies_n,
+                    });
+                }
+
+                // The root rounded, as that of a perfect power may land just above it
+                let mut side = (bodies_n as f64).powf(1.0 / DIMENSIONS as f64).round() as usize;
+                if side.pow(DIMENSIONS as u32) < bodies_n {
+                    side += 1;
+                }
+                let offset = Vector::splat((side - 1) as f64 * spacing / 2.0);
+
+                masses
+                    .iter()
+                    .enumerate()
+                    .map(|(index, &mass)| {
+                        let pos = Vector::from_components(std::array::from_fn(|k| {
+                            (index / side.pow(k as u32) % side) as f64 * spacing
+                        }));
+
+                        new_body(center - offset + pos, Vector::ZERO, mass, density, &mut rng)
+                    })
+                    .collect()
+            }
+            Self::TwoGalaxies {
+                center,
+                central_mass,
+                radius,
+                separation,
+                impact_parameter,
+                approach_speed,
+            } => {
+                let offset = Vector::from_xy(separation / 2.0, impact_parameter / 2.0);
+                let speed = Vector::from_xy(approach_speed / 2.0, 0.0);
+
+                let (first, second) = masses.split_at(bodies_n.div_ceil(2));
+
+                [(center - offset, speed), (center + offset, -speed)]
+                    .into_iter()
+                    .zip([first, second])
+                    .flat_map(|((center, speed), masses)| {
+                        let mut galaxy = get_keplerian_disk(
+                            masses,
+                            center,
+                            central_mass,
+                            [radius / 10.0, radius],
+                            &mut placement,
+                            &mut rng,
+                        );
+                        for body in &mut galaxy {
+                            body.speed += speed;
+                        }
+
+                        galaxy
+                    })
+                    .collect()
+            }
+            Self::SolarSystem {
+                center,
+                star_mass,
+                earth_mass,
+                planets_n,
+                innermost_orbit,
+                orbit_ratio,
+            } => {
+                let mut bodies = Vec::with_capacity(bodies_n);
+                let star = new_body(center, Vector::ZERO, star_mass, density, &mut rng);
+                placement.insert(star.pos, star.radius);
+                bodies.push(star);
+
+                let planets_n = planets_n.min(PLANET_MASSES.len()).min(bodies_n - 1);
+                for (k, mass_ratio) in PLANET_MASSES.into_iter().take(planets_n).enumerate() {
+                    let distance = innermost_orbit * orbit_ratio.powi(k as i32);
+                    let mass = mass_ratio * earth_mass;
+                    let pos = placement.place(mass, || {
+                        center + distance * get_xy_direction(rng.random_range(0.0..2.0 * PI))
+                    });
+
+                    let speed = get_circular_speed(G.get() * star_mass, pos - center);
+                    bodies.push(new_body(pos, speed, mass, density, &mut rng));
+                }
+
+                for &mass in &masses[bodies.len()..] {
+                    let pos = placement.place(mass, || {
+                        let distance =
+                            innermost_orbit * orbit_ratio.powf(rng.random_range(BELT[0]..BELT[1]));
+                        center + distance * get_xy_direction(rng.random_range(0.0..2.0 * PI))
+                    });
+
+                    let speed = get_circular_speed(G.get() * star_mass, pos - center);
+                    bodies.push(new_body(pos, speed, mass, density, &mut rng));
+                }
+
+                bodies
+            }
+        };
+
+        if placement.crowded {
+            return Err(CrowdedError {
+                name: self.name(),
+                bodies_n,
+            });
+        }
+
+        Ok(bodies
+            .into_iter()
+            .map(|body| (new_body_id(), body))
+            .collect())
+    }
+}
+
+fn new_body(pos: Vector, speed: Vector, mass: f64, density: f64, rng: &mut StdRng) -> Body {
+    Body {
+        pos,
+        speed,
+        mass,
+        radius: Body::get_radius(mass, density),
+        density,
+        charge: if rng.random_bool(0.5) {
+            INITIAL_CHARGE
+        } else {
+            -INITIAL_CHARGE
+        },
+        species: 0,
+    }
+}
+
+fn get_xy_direction(angle: f64) -> Vector {
+    Vector::from_xy(angle.cos(), angle.sin())
+}
+
+/// The speed of a circular orbit at `r` in the xy plane around `gm`, counterclockwise.
+fn get_circular_speed(gm: f64, r: Vector) -> Vector {
+    let distance = r.length();
+    (gm / distance).sqrt() / distance * Vector::from_xy(-r.y(), r.x())
+}
+
+/// A standard normal variate, by the Box-Muller transform.
+fn get_normal(rng: &mut StdRng) -> f64 {
+    let u = 1.0 - rng.random_range(0.0..1.0f64);
+    let angle = rng.random_range(0.0..2.0 * PI);
+
+    (-2.0 * u.ln()).sqrt() * angle.cos()
+}
+
+/// The distribution the masses of the generated bodies are drawn from.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum MassSpectrum {
+    Equal(f64),
+    /// `dN/dm` proportional to `m^-exponent` between `min` and `max`.
+    PowerLaw {
+        exponent: f64,
+        min: f64,
+        max: f64,
+    },
+    /// The power law of the initial mass function of Salpeter (1955), `dN/dm ~ m^-2.35`.
+    Salpeter {
+        min: f64,
+        max: f64,
+    },
+    /// `ln m` normally distributed around `ln median` with the deviation `sigma`.
+    LogNormal {
+        median: f64,
+        sigma: f64,
+    },
+}
+
+impl MassSpectrum {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Equal(_) => "Equal",
+            Self::PowerLaw { .. } => "Power law",
+            Self::Salpeter { .. } => "Salpeter",
+            Self::LogNormal { .. } => "Log-normal",
+        }
+    }
+
+    /// The same spectrum with the masses of a scenario's units in the simulation's.
+    pub fn to_simulation(self, scales: &Scales) -> Self {
+        let mass = |mass| scales.in_simulation(Quantity::Mass, mass);
+
+        match self {
+            Self::Equal(equal) => Self::Equal(mass(equal)),
+            Self::PowerLaw { exponent, min, max } => Self::PowerLaw {
+                exponent,
+                min: mass(min),
+                max: mass(max),
+            },
+            Self::Salpeter { min, max } => Self::Salpeter {
+                min: mass(min),
+                max: mass(max),
+            },
+            Self::LogNormal { median, sigma } => Self::LogNormal {
+                median: mass(median),
+                sigma,
+            },
+        }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> f64 {
+        match *self {
+            Self::Equal(mass) => mass,
+            Self::PowerLaw { exponent, min, max } => {
+                let fraction = rng.random_range(0.0..1.0f64);
+                // Inverting the cumulative distribution
+                if exponent == 1.0 {
+                    min * (max / min).powf(fraction)
+                } else {
+                    let power = 1.0 - exponent;
+                    (min.powf(power) + fraction * (max.powf(power) - min.powf(power)))
+                        .powf(1.0 / power)
+                }
+            }
+            Self::Salpeter { min, max } => Self::PowerLaw {
+                exponent: 2.35,
+                min,
+                max,
+            }
+            .sample(rng),
+            Self::LogNormal { median, sigma } => median * (sigma * get_normal(rng)).exp(),
+        }
+    }
+}
+
+/// The velocity dispersion of `InitialConditions::RotatingDisk`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dispersion {
+    /// The radial dispersion giving the stellar Toomre parameter
+    /// `Q = sigma_R kappa / (3.36 G Sigma)` at every radius.
+    ToomreQ(f64),
+    /// The same radial dispersion at every radius.
+    Constant(f64),
+}
+
+/// The accelerations of `bodies`, as Barnes-Hut finds them under the current
+/// force law, plus the external fields. The tree is walked without the solver,
+/// which would draw it before there is a window.
+fn get_accelerations(bodies: &[Body]) -> Vec<Vector> {
+    let ids = (0..bodies.len() as BodyID).collect::<Vec<_>>();
+    let mut at_rest = ids
+        .iter()
+        .zip(bodies)
+        .map(|(&id, body)| {
+            let body = Body {
+                speed: Vector::ZERO,
+                ..*body
+            };
+            (id, body)
+        })
+        .collect();
+
+    let force_law = *FORCE_LAW.read().unwrap();
+    let tree = Tree::new(&at_rest, force_law);
+    let mut stack = Vec::new();
+    for (index, (_, id)) in tree.bodies.iter().enumerate() {
+        tree.adjust_speed(
+            index,
+            at_rest.get_mut(id).unwrap(),
+            Mac::Geometric,
+            GENERATION_THETA,
+            0.0,
+            force_law,
+            &mut stack,
+        );
+    }
+
+    let external_fields = EXTERNAL_FIELDS.read().unwrap();
+    ids.iter()
+        .map(|id| {
+            let body = at_rest[id];
+            body.speed / DT.get() + get_external_acceleration(&external_fields, body.pos, 0.0)
+        })
+        .collect()
+}
+
+/// The squared circular speed against the radius, averaged over rings
+/// holding the same number of bodies to smooth out their graininess.
+struct RotationCurve {
+    radii: Vec<f64>,
+    squared_speeds: Vec<f64>,
+}
+
+impl RotationCurve {
+    /// From the radius and the squared circular speed at every body.
+    fn new(mut samples: Vec<(f64, f64)>) -> Self {
+        samples.sort_by(|lhs, rhs| lhs.0.total_cmp(&rhs.0));
+
+        let (radii, squared_speeds) = samples
+            .chunks(samples.len().div_ceil(RINGS_N).max(1))
+            .map(|ring| {
+                let n = ring.len() as f64;
+                (
+                    ring.iter().map(|sample| sample.0).sum::<f64>() / n,
+                    ring.iter().map(|sample| sample.1).sum::<f64>() / n,
+                )
+            })
+            .unzip();
+
+        Self {
+            radii,
+            squared_speeds,
+        }
+    }
+
+    /// The squared circular speed at `r` and its derivative, linearly interpolated.
+    fn get(&self, r: f64) -> (f64, f64) {
+        if self.radii.len() < 2 {
+            return (self.squared_speeds.first().copied().unwrap_or(0.0), 0.0);
+        }
+
+        let index = self
+            .radii
+            .partition_point(|&radius| radius < r)
+            .clamp(1, self.radii.len() - 1);
+        let (r0, r1) = (self.radii[index - 1], self.radii[index]);
+        let (v0, v1) = (self.squared_speeds[index - 1], self.squared_speeds[index]);
+
+        let slope = (v1 - v0) / (r1 - r0).max(f64::MIN_POSITIVE);
+        let squared_speed = (v0 + slope * (r.clamp(r0, r1) - r0)).max(0.0);
+
+        (squared_speed, slope)
+    }
+}
+
+fn get_rotating_disk(
+    masses: &[f64],
+    center: Vector,
+    scale_length: f64,
+    dispersion: Dispersion,
+    placement: &mut Placement,
+    rng: &mut StdRng,
+) -> Vec<Body> {
+    let scale_height = DISK_THICKNESS * scale_length;
+    let total_mass = masses.iter().sum::<f64>();
+    let get_surface_density =
+        |r: f64| total_mass / (2.0 * PI * scale_length.powi(2)) * (-r / scale_length).exp();
+
+    let mut bodies = masses
+        .iter()
+        .map(|&mass| {
+            let pos = placement.place(mass, || {
+                // The radii of an exponential disk are gamma distributed with the shape 2
+                let r = loop {
+                    let r = -scale_length
+                        * ((1.0 - rng.random_range(0.0..1.0f64)).ln()
+                            + (1.0 - rng.random_range(0.0..1.0f64)).ln());
+                    if r < DISK_TRUNCATION * scale_length {
+                        break r;
+                    }
+                };
+
+                let mut offset =
+                    (r * get_xy_direction(rng.random_range(0.0..2.0 * PI))).components();
+                // In 3D the disk is an isothermal sheet, of the density `sech^2(z / z0)`
+                for component in offset.iter_mut().skip(2) {
+                    *component = scale_height * rng.random_range(-1.0..1.0f64).atanh();
+                }
+
+                center + Vector::from_components(offset)
+            });
+
+            new_body(pos, Vector::ZERO, mass, placement.density, rng)
+        })
+        .collect::<Vec<_>>();
+
+    let in_plane = |pos: Vector| {
+        let r = pos - center;
+        Vector::from_xy(r.x(), r.y())
+    };
+
+    let accelerations = get_accelerations(&bodies);
+    let rotation_curve = RotationCurve::new(
+        bodies
+            .iter()
+            .zip(&accelerations)
+            .map(|(body, acceleration)| {
+                let r = in_plane(body.pos);
+                (r.length(), -acceleration.dot(r))
+            })
+            .collect(),
+    );
+
+    for body in &mut bodies {
+        let r = in_plane(body.pos);
+        let distance = r.length();
+        if distance == 0.0 {
+            continue;
+        }
+        let radial = r / distance;
+        let azimuthal = Vector::from_xy(-radial.y(), radial.x());
+
+        let (squared_speed, slope) = rotation_curve.get(distance);
+        let squared_angular_speed = squared_speed / distance.powi(2);
+        // `kappa^2 / (4 Omega^2)`, kept between the Keplerian and the solid body
+        // rotation against the noise of the measured curve
+        let epicyclic_ratio = if squared_angular_speed > 0.0 {
+            ((slope / distance + 2.0 * squared_angular_speed) / (4.0 * squared_angular_speed))
+                .clamp(0.25, 1.0)
+        } else {
+            1.0
+        };
+        let epicyclic_frequency = (4.0 * squared_angular_speed * epicyclic_ratio).sqrt();
+
+        let radial_dispersion = match dispersion {
+            Dispersion::ToomreQ(_) if epicyclic_frequency == 0.0 => 0.0,
+            Dispersion::ToomreQ(q) => {
+                q * 3.36 * G.get() * get_surface_density(distance) / epicyclic_frequency
+            }
+            Dispersion::Constant(dispersion) => dispersion,
+        };
+
+        // The epicyclic approximation, and the asymmetric drift of an exponential
+        // disk whose squared dispersion falls off with the density
+        let azimuthal_dispersion = radial_dispersion * epicyclic_ratio.sqrt();
+        let mean_speed = (squared_speed
+            + radial_dispersion.powi(2) * (1.0 - epicyclic_ratio - 2.0 * distance / scale_length))
+            .max(0.0)
+            .sqrt();
+
+        body.speed = radial_dispersion * get_normal(rng) * radial
+            + (mean_speed + azimuthal_dispersion * get_normal(rng)) * azimuthal;
+        let vertical_dispersion =
+            (PI * G.get() * get_surface_density(distance) * scale_height).sqrt();
+        let mut components = body.speed.components();
+        for component in components.iter_mut().skip(2) {
+            *component = vertical_dispersion * get_normal(rng);
+        }
+        body.speed = Vector::from_components(components);
+    }
+
+    bodies
+}
+
+/// A body of `central_mass` at `center`, standing in for the first of `masses`,
+/// and the others around it in the xy plane.
+fn get_keplerian_disk(
+    masses: &[f64],
+    center: Vector,
+    central_mass: f64,
+    [inner_radius, outer_radius]: [f64; 2],
+    placement: &mut Placement,
+    rng: &mut StdRng,
+) -> Vec<Body> {
+    let mut bodies = Vec::with_capacity(masses.len());
+    if masses.is_empty() {
+        return bodies;
+    }
+
+    let central = new_body(center, Vector::ZERO, central_mass, placement.density, rng);
+    placement.insert(central.pos, central.radius);
+    bodies.push(central);
+
+    for &mass in &masses[1..] {
+        let pos = placement.place(mass, || {
+            let distance = rng
+                .random_range(inner_radius.powi(2)..outer_radius.powi(2))
+                .sqrt();
+            center + distance * get_xy_direction(rng.random_range(0.0..2.0 * PI))
+        });
+
+        let speed = get_circular_speed(G.get() * central_mass, pos - center);
+        bodies.push(new_body(pos, speed, mass, placement.density, rng));
+    }
+
+    bodies
+}
+
+/// Keeps the generated bodies from overlapping, which would merge them on the
+/// first step, by redrawing the positions that land too close to a placed body.
+/// Bodies no larger than a cell are hashed into the cells, the few larger ones
+/// are checked one by one.
+struct Placement {
+    /// That of the bodies, which sets their radii.
+    density: f64,
+    cell_side: f64,
+    cells: HashMap<[i64; DIMENSIONS], Vec<(Vector, f64)>>,
+    large: Vec<(Vector, f64)>,
+    /// Whether a body found no room, after which the others aren't looked for
+    /// room either, as the bodies are given up on.
+    crowded: bool,
+}
+
+impl Placement {
+    fn new(cell_side: f64, density: f64) -> Self {
+        Self {
+            density,
+            cell_side,
+            cells: HashMap::new(),
+            large: Vec::new(),
+            crowded: false,
+        }
+    }
+
+    fn get_cell(&self, pos: Vector) -> [i64; DIMENSIONS] {
+        pos.components()
+            .map(|component| (component / self.cell_side).floor() as i64)
+    }
+
+    fn is_free(&self, pos: Vector, radius: f64) -> bool {
+        let overlaps = |(other, other_radius): &(Vector, f64)| {
+            (*other - pos).length() <= radius + other_radius
+        };
+        if self.large.iter().any(overlaps) {
+            return false;
+        }
+
+        let reach = (radius / self.cell_side).ceil() as i64 + 1;
+        let cell = self.get_cell(pos);
+        let side = 2 * reach as usize + 1;
+
+        (0..side.pow(DIMENSIONS as u32)).all(|index| {
+            let neighbour = std::array::from_fn(|k| {
+                cell[k] + (index / side.pow(k as u32) % side) as i64 - reach
+            });
+
+            self.cells
+                .get(&neighbour)
+                .is_none_or(|placed| !placed.iter().any(overlaps))
+        })
+    }
+
+    fn insert(&mut self, pos: Vector, radius: f64) {
+        if radius > self.cell_side {
+            self.large.push((pos, radius));
+        } else {
+            let cell = self.get_cell(pos);
+            self.cells.entry(cell).or_default().push((pos, radius));
+        }
+    }
+
+    /// Draws positions of a body of `mass` until one is free, and takes it.
+    /// Sets `crowded` if none is within `MAX_PLACEMENT_ATTEMPTS`.
+    fn place(&mut self, mass: f64, mut draw: impl FnMut() -> Vector) -> Vector {
+        if !self.crowded {
+            let radius = Body::get_radius(mass, self.density);
+
+            for _ in 0..MAX_PLACEMENT_ATTEMPTS {
+                let pos = draw();
+                if self.is_free(pos, radius) {
+                    self.insert(pos, radius);
+                    return pos;
+                }
+            }
+            self.crowded = true;
+        }
+
+        draw()
+    }
+}
+
+/// The dimensionless King (1966) model, in King radii and with the central density
+/// being one: the potential `W` and the enclosed mass against the radius, out to
+/// the tidal radius where `W` falls to zero.
+struct KingProfile {
+    radii: Vec<f64>,
+    potentials: Vec<f64>,
+    masses: Vec<f64>,
+}
+
+impl KingProfile {
+    /// The density at the potential `w`, relative to that at `w0`.
+    fn get_density(w: f64, w0: f64) -> f64 {
+        let density = |w: f64| {
+            if w <= 0.0 {
+                return 0.0;
+            }
+
+            // Cancels to the order `w^(5/2)`, so may come out slightly negative near zero
+            w.exp() * erf(w.sqrt()) - (4.0 * w / PI).sqrt() * (1.0 + 2.0 * w / 3.0)
+        };
+
+        (density(w) / density(w0)).max(0.0)
+    }
+
+    /// Integrates `W'' + 2 W' / r = -9 rho(W)` outwards from `W(0) = w0`, `W'(0) = 0`.
+    fn new(w0: f64) -> Self {
+        let mut profile = Self {
+            radii: vec![0.0],
+            potentials: vec![w0],
+            masses: vec![0.0],
+        };
+
+        // The state is `(W, W', m)`, with `m' = r^2 rho`
+        let derivative = |r: f64, [w, dw, _]: [f64; 3]| {
+            let density = Self::get_density(w, w0);
+            let d2w = if r == 0.0 {
+                // The limit of `2 W' / r` is `2 W''(0)`
+                -3.0 * density
+            } else {
+                -9.0 * density - 2.0 * dw / r
+            };
+
+            [dw, d2w, r.powi(2) * density]
+        };
+
+        let (mut r, mut state) = (0.0, [w0, 0.0, 0.0]);
+        while state[0] > 0.0 {
+            let h = KING_STEP;
+            let add = |state: [f64; 3], k: [f64; 3], factor: f64| {
+                std::array::from_fn(|i| state[i] + factor * k[i])
+            };
+
+            let k1 = derivative(r, state);
+            let k2 = derivative(r + h / 2.0, add(state, k1, h / 2.0));
+            let k3 = derivative(r + h / 2.0, add(state, k2, h / 2.0));
+            let k4 = derivative(r + h, add(state, k3, h));
+            state = std::array::from_fn(|i| {
+                state[i] + h / 6.0 * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i])
+            });
+            r += h;
+
+            profile.radii.push(r);
+            profile.potentials.push(state[0].max(0.0));
+            profile.masses.push(state[2]);
+        }
+
+        profile
+    }
+
+    fn get_total_mass(&self) -> f64 {
+        *self.masses.last().unwrap()
+    }
+
+    /// A radius distributed as the mass, with the potential there.
+    fn sample(&self, rng: &mut StdRng) -> (f64, f64) {
+        let mass = rng.random_range(0.0..self.get_total_mass());
+        let index = self
+            .masses
+            .partition_point(|&enclosed| enclosed < mass)
+            .clamp(1, self.masses.len() - 1);
+
+        let fraction = (mass - self.masses[index - 1])
+            / (self.masses[index] - self.masses[index - 1]).max(f64::MIN_POSITIVE);
+        let lerp = |values: &[f64]| {
+            values[index - 1] + fraction.clamp(0.0, 1.0) * (values[index] - values[index - 1])
+        };
+
+        (lerp(&self.radii), lerp(&self.potentials))
+    }
+
+    /// A speed in units of the dispersion, distributed as `v^2 (e^(w - v^2 / 2) - 1)`
+    /// below the escape speed `sqrt(2 w)`.
+    fn sample_speed(w: f64, rng: &mut StdRng) -> f64 {
+        let escape_speed = (2.0 * w).sqrt();
+        let bound = escape_speed.powi(2) * w.exp_m1();
+        if bound <= 0.0 {
+            return 0.0;
+        }
+
+        loop {
+            let speed = rng.random_range(0.0..escape_speed);
+            if rng.random_range(0.0..bound) < speed.powi(2) * (w - speed.powi(2) / 2.0).exp_m1() {
+                return speed;
+            }
+        }
+    }
+}
+
+/// The error function, by Abramowitz and Stegun 7.1.26, to about 1e-7.
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+
+    (1.0 - polynomial * (-x * x).exp()).copysign(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCALE_LENGTH: f64 = 150.0;
+    const BODIES_N: usize = 4000;
+    const RING_BODIES_N: usize = 400;
+
+    /// The distance from the axis of the disk.
+    fn get_radius(body: &Body) -> f64 {
+        body.pos.x().hypot(body.pos.y())
+    }
+
+    /// The bodies of a disk with their accelerations, from the axis out.
+    fn get_disk(dispersion: Dispersion) -> Vec<(Body, Vector)> {
+        let bodies = InitialConditions::RotatingDisk {
+            center: Vector::ZERO,
+            scale_length: SCALE_LENGTH,
+            dispersion,
+        }
+        .generate(BODIES_N, MassSpectrum::Equal(1.0), 1.0, 0)
+        .unwrap()
+        .into_values()
+        .collect::<Vec<_>>();
+
+        let accelerations = get_accelerations(&bodies);
+        let mut disk = bodies.into_iter().zip(accelerations).collect::<Vec<_>>();
+        disk.sort_by(|lhs, rhs| get_radius(&lhs.0).total_cmp(&get_radius(&rhs.0)));
+
+        disk
+    }
+
+    /// The mean of `get` over `ring`.
+    fn get_mean(ring: &[(Body, Vector)], get: impl Fn(&Body, Vector) -> f64) -> f64 {
+        ring.iter()
+            .map(|(body, acceleration)| get(body, *acceleration))
+            .sum::<f64>()
+            / ring.len() as f64
+    }
+
+    fn get_radial_speed(body: &Body, _: Vector) -> f64 {
+        (body.pos.x() * body.speed.x() + body.pos.y() * body.speed.y()) / get_radius(body)
+    }
+
+    fn get_azimuthal_speed(body: &Body, _: Vector) -> f64 {
+        (body.pos.x() * body.speed.y() - body.pos.y() * body.speed.x()) / get_radius(body)
+    }
+
+    /// The squared circular speed the acceleration towards the axis calls for.
+    fn get_squared_circular_speed(body: &Body, acceleration: Vector) -> f64 {
+        -(body.pos.x() * acceleration.x() + body.pos.y() * acceleration.y())
+    }
+
+    #[test]
+    fn rotating_disk_rotation() {
+        // Without a dispersion there is no asymmetric drift, so the rings turn
+        // at the circular speed. The innermost and the outermost one are too
+        // grainy to compare.
+        let disk = get_disk(Dispersion::Constant(0.0));
+        let rings = disk.chunks(RING_BODIES_N).collect::<Vec<_>>();
+
+        for ring in &rings[1..rings.len() - 1] {
+            let speed = get_mean(ring, get_azimuthal_speed);
+            let circular_speed = get_mean(ring, get_squared_circular_speed).sqrt();
+
+            assert!(
+                (speed - circular_speed).abs() < 0.03 * circular_speed,
+                "{} instead of {} at {}",
+                speed,
+                circular_speed,
+                get_radius(&ring[0].0)
+            );
+        }
+    }
+
+    #[test]
+    fn rotating_disk_toomre_q() {
+        let q = 1.5;
+        let disk = get_disk(Dispersion::ToomreQ(q));
+        let rings = disk.chunks(RING_BODIES_N).collect::<Vec<_>>();
+
+        // The radius and the squared angular speed of every ring, for the epicyclic frequency
+        let curve = rings
+            .iter()
+            .map(|ring| {
+                let radius = get_mean(ring, |body, _| get_radius(body));
+                (
+                    radius,
+                    get_mean(ring, get_squared_circular_speed) / radius.powi(2),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Averaged over the rings, as the dispersion and the slope of a single one are noisy
+        let inner_rings = 1..rings.len() - 1;
+        let measured = inner_rings
+            .clone()
+            .map(|index| {
+                let ring = rings[index];
+                let (radius, squared_angular_speed) = curve[index];
+                let slope = (curve[index + 1].1 - curve[index - 1].1)
+                    / (curve[index + 1].0 - curve[index - 1].0);
+                let epicyclic_frequency = (radius * slope + 4.0 * squared_angular_speed).sqrt();
+
+                let mean = get_mean(ring, get_radial_speed);
+                let radial_dispersion = (get_mean(ring, |body, acceleration| {
+                    get_radial_speed(body, acceleration).powi(2)
+                }) - mean.powi(2))
+                .sqrt();
+
+                let [inner, outer] =
+                    [ring[0].0, ring[ring.len() - 1].0].map(|body| get_radius(&body));
+                let surface_density = get_mean(ring, |body, _| body.mass) * ring.len() as f64
+                    / (PI * (outer.powi(2) - inner.powi(2)));
+
+                radial_dispersion * epicyclic_frequency / (3.36 * G.get() * surface_density)
+            })
+            .sum::<f64>()
+            / inner_rings.len() as f64;
+
+        assert!(
+            (measured - q).abs() < 0.1 * q,
+            "Q = {} instead of {}",
+            measured,
+            q
+        );
+    }
+
+    /// A perfect power of bodies fills the lattice, centred where it should be.
+    #[test]
+    fn lattice_perfect_power() {
+        let spacing = 10.0;
+
+        for side in [3usize, 4, 5] {
+            let bodies = InitialConditions::Lattice {
+                center: Vector::ZERO,
+                spacing,
+            }
+            .generate(
+                side.pow(DIMENSIONS as u32),
+                MassSpectrum::Equal(1.0),
+                1.0,
+                0,
+            )
+            .unwrap();
+
+            let center = bodies.values().map(|body| body.pos).sum::<Vector>() / bodies.len() as f64;
+            assert!(center.length() < 1e-9, "{:?} with {} a side", center, side);
+            for k in 0..DIMENSIONS {
+                let max = bodies
+                    .values()
+                    .map(|body| body.pos.components()[k])
+                    .fold(f64::NEG_INFINITY, f64::max);
+                assert!(
+                    (max - (side - 1) as f64 * spacing / 2.0).abs() < 1e-9,
+                    "{} along {} with {} a side",
+                    max,
+                    k,
+                    side
+                );
+            }
+        }
+    }
+}
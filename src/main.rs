@@ -1,206 +1,762 @@
 mod barnes_hut;
 mod body;
+mod camera;
+mod cli;
 mod direct;
+mod drag;
+mod expansion;
+mod external_field;
+mod force_law;
+mod frame;
 mod grid;
-mod zoom;
+mod headless;
+mod import;
+mod initial_conditions;
+mod multigrid;
+mod orbit;
+mod output;
+mod post_newtonian;
+mod scenario;
+mod snapshot;
+mod solver;
+mod units;
+mod vector;
 
 use ::rand::{Rng, SeedableRng, rngs::StdRng};
-use barnes_hut::{BarnesHut, ThetaAdjustment};
-use body::{BODIES_N, Body, BodyID, INITIAL_ABS_SPEED, INITIAL_MASS};
-use direct::Direct;
-use grid::Grid;
+use barnes_hut::{BarnesHut, Mac, ThetaAdjustment, Traversal};
+use body::{Body, BodyID, DT, INITIAL_ABS_SPEED, INITIAL_MASS};
+use camera::{Camera, Follow, ZOOM_STEP};
+use clap::Parser;
+use cli::{Cli, CliError, Command, ConvertArgs};
+use drag::{DUST, Drag, MEDIUM, Medium, SPECIES};
+use external_field::{EXTERNAL_FIELDS, ExternalField};
+use force_law::{FORCE_LAW, ForceLaw, RADIAL_LAWS, YUKAWA_LENGTH};
+use grid::{Grid, SIZING, TAU, TauAdjustment};
+use import::Import;
+use initial_conditions::{CrowdedError, Dispersion, InitialConditions, MassSpectrum};
 use macroquad::prelude::*;
-use num_complex::{Complex, ComplexFloat};
+use orbit::{ORBIT, ORBIT_STEP};
+use output::Trajectory;
+use post_newtonian::{
+    DEFAULT_SPEED_OF_LIGHT, SPEED_OF_LIGHT, SpeedOfLightAdjustment, adjust_speed_of_light,
+    toggle_post_newtonian,
+};
+use rand_chacha::ChaCha12Rng;
+use scenario::{Forces, FrameConfig, Generation, Scenario};
+use snapshot::Snapshot;
+use solver::{Simulation, Solver};
 use std::{
     collections::HashMap,
-    f64::consts::{PI, SQRT_2},
+    fs,
     num::NonZero,
+    path::{Path, PathBuf},
 };
-use zoom::{
-    Zoom, {ZOOM_RANGE, ZOOM_STEP},
-};
+use units::{Quantity, SCALES};
+use vector::{Vector, VectorExt};
 
 const MAX_AVERAGE_LENGTH: NonZero<usize> = NonZero::new(100).unwrap();
 
 const FONT_SIZE: u16 = 50;
 
-const DT: f64 = 1.0;
+/// The force laws to cycle through, the last one being an example of a user-supplied law.
+/// The logarithmic law is the gravity of a 2D world, so 3D leaves it out.
+const FORCE_LAWS: &[ForceLaw] = &[
+    ForceLaw::Newtonian,
+    #[cfg(not(feature = "3d"))]
+    ForceLaw::Logarithmic,
+    ForceLaw::Coulomb,
+    ForceLaw::Yukawa {
+        length: YUKAWA_LENGTH,
+    },
+    ForceLaw::Radial(RADIAL_LAWS[0]),
+];
+
+/// The mass spectra to cycle through, of about `INITIAL_MASS` on average.
+const MASS_SPECTRA: [MassSpectrum; 4] = [
+    MassSpectrum::Equal(INITIAL_MASS),
+    MassSpectrum::PowerLaw {
+        exponent: 1.5,
+        min: 0.25,
+        max: 4.0,
+    },
+    MassSpectrum::Salpeter {
+        min: 0.4,
+        max: 20.0,
+    },
+    MassSpectrum::LogNormal {
+        median: 0.8,
+        sigma: 0.6,
+    },
+];
+
+/// The drag laws of the dust to cycle through.
+const DRAG_LAWS: [Drag; 4] = [
+    Drag::Epstein,
+    Drag::Stokes,
+    Drag::Linear { rate: 0.01 },
+    Drag::Quadratic { coefficient: 0.2 },
+];
+
+/// The external fields to cycle through, around the centre of the initial disk.
+fn get_external_field_presets(center: Vector) -> [Vec<ExternalField>; 6] {
+    let x = Vector::from_xy(1.0, 0.0);
+
+    let halo = ExternalField::LogarithmicHalo {
+        pos: center,
+        circular_speed: 0.3,
+        core_radius: 100.0,
+    };
+
+    [
+        Vec::new(),
+        // A central black hole
+        vec![ExternalField::PointMass {
+            pos: center,
+            mass: 2000.0,
+        }],
+        vec![halo],
+        vec![
+            halo,
+            ExternalField::RotatingBar {
+                pos: center,
+                mass: 500.0,
+                half_length: 200.0,
+                radius: 50.0,
+                pattern_speed: 0.0005,
+            },
+        ],
+        // A host galaxy far along the x axis, pulling the disk apart beyond about 700
+        vec![ExternalField::Tidal {
+            pos: center,
+            direction: x,
+            strength: 7e-8,
+        }],
+        vec![ExternalField::Uniform {
+            acceleration: 1e-4 * x,
+        }],
+    ]
+}
 
-pub const BORDER_THICKNESS: f32 = 2.0;
-pub const BORDER_COLOR: Color = GREEN;
+/// The medium the D key turns on when the scenario has none.
+fn get_medium_preset(center: Vector) -> Medium {
+    Medium {
+        center,
+        circular_speed: 0.0,
+        density: 0.01,
+        thermal_speed: 1.0,
+        mean_free_path: 1.0,
+    }
+}
 
-fn window_conf() -> Conf {
-    Conf {
-        window_title: "gravity".to_owned(),
-        fullscreen: true,
-        platform: miniquad::conf::Platform {
-            linux_backend: miniquad::conf::LinuxBackend::WaylandWithX11Fallback,
-            ..Default::default()
+/// The initial conditions to cycle through, filling the world around `center`.
+fn get_initial_conditions_presets(center: Vector) -> [InitialConditions; 10] {
+    [
+        InitialConditions::UniformDisk {
+            center,
+            radius: WORLD_SIZE[0] / 2.0,
+            aspect: WORLD_SIZE[1] / WORLD_SIZE[0],
+            speed: INITIAL_ABS_SPEED,
         },
-        ..Default::default()
+        InitialConditions::Plummer {
+            center,
+            radius: 150.0,
+        },
+        InitialConditions::Hernquist {
+            center,
+            radius: 150.0,
+        },
+        InitialConditions::King {
+            center,
+            radius: 50.0,
+            concentration: 6.0,
+        },
+        InitialConditions::KeplerianDisk {
+            center,
+            central_mass: 2000.0,
+            inner_radius: 50.0,
+            outer_radius: WORLD_SIZE[1] / 2.0,
+        },
+        InitialConditions::RotatingDisk {
+            center,
+            scale_length: 150.0,
+            dispersion: Dispersion::ToomreQ(1.5),
+        },
+        InitialConditions::RotatingDisk {
+            center,
+            scale_length: 150.0,
+            dispersion: Dispersion::Constant(0.05),
+        },
+        InitialConditions::Lattice {
+            center,
+            spacing: 20.0,
+        },
+        InitialConditions::TwoGalaxies {
+            center,
+            central_mass: 1000.0,
+            radius: 250.0,
+            separation: 800.0,
+            impact_parameter: 200.0,
+            approach_speed: 0.2,
+        },
+        InitialConditions::SolarSystem {
+            center,
+            star_mass: 3000.0,
+            earth_mass: 5.0,
+            planets_n: 8,
+            innermost_orbit: 60.0,
+            orbit_ratio: 1.4,
+        },
+    ]
+}
+
+/// The bodies of `initial_conditions`, as many and as dense as `generation` asks,
+/// with its dust fraction of them being dust. The same for the same `seed`, like
+/// the generators.
+fn generate_bodies(
+    initial_conditions: InitialConditions,
+    mass_spectrum: MassSpectrum,
+    generation: &Generation,
+    seed: u64,
+) -> Result<HashMap<BodyID, Body>, CrowdedError> {
+    let mut bodies = initial_conditions.generate(
+        generation.bodies_n.get(),
+        mass_spectrum,
+        generation.density,
+        seed,
+    )?;
+    // A stream apart from the generator's, over the bodies in the order they were generated in
+    let mut rng = StdRng::seed_from_u64(!seed);
+    let mut body_ids = bodies.keys().copied().collect::<Vec<_>>();
+    body_ids.sort();
+    for body_id in body_ids {
+        let body = bodies.get_mut(&body_id).unwrap();
+        if rng.random_bool(generation.dust_fraction) {
+            body.species = DUST;
+        }
     }
+    Body::adjust_momentum(&mut bodies);
+
+    Ok(bodies)
 }
 
-#[macroquad::main(window_conf)]
-async fn main() {
-    let mut rng = StdRng::from_os_rng();
+/// Records `simulations` to `trajectories`, an output that fails being given up on
+/// and the others carrying on.
+fn record(trajectories: &mut Vec<Trajectory>, simulations: &[Simulation]) {
+    trajectories.retain_mut(|trajectory| match trajectory.record(simulations) {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("{}: {}", trajectory.path.display(), error);
+            false
+        }
+    });
+}
 
-    for _ in 0..8 {
-        set_fullscreen(true);
-        next_frame().await;
+/// Closes `trajectories` once the simulations start over, so that a file only
+/// ever holds the one run.
+fn stop_recording(trajectories: &mut Vec<Trajectory>) {
+    for trajectory in trajectories.drain(..) {
+        eprintln!(
+            "{}: stopped recording, as the simulations started over",
+            trajectory.path.display()
+        );
+    }
+}
+
+/// The bodies of the file of `import`, relative to `directory`, those without a radius
+/// getting `density`. The species are looked up among those the scenario set.
+fn import_bodies(
+    import: &Import,
+    directory: &Path,
+    density: f64,
+) -> Result<HashMap<BodyID, Body>, CliError> {
+    import
+        .load(directory, density)
+        .map_err(|error| CliError::Import(directory.join(&import.path), error))
+}
+
+/// The initial conditions and mass spectra to cycle through, those of `generation`
+/// first, in the simulation's units.
+fn get_presets(
+    generation: &Generation,
+    center: Vector,
+) -> (Vec<InitialConditions>, Vec<MassSpectrum>) {
+    let mut initial_conditions_presets = get_initial_conditions_presets(center).to_vec();
+    if let Some(mut generator) = generation.generator {
+        generator.set_center(center);
+        initial_conditions_presets.insert(0, generator);
     }
 
-    let mut zoom = Zoom { zoom: 1.0 };
+    let mut mass_spectra = MASS_SPECTRA.to_vec();
+    if mass_spectra[0] != generation.mass_spectrum {
+        mass_spectra.insert(0, generation.mass_spectrum);
+    }
 
-    let mut camera =
-        Camera2D::from_display_rect(Rect::new(0.0, 0.0, screen_width(), screen_height()));
+    (initial_conditions_presets, mass_spectra)
+}
 
-    let mut bodies = HashMap::with_capacity(BODIES_N.get());
+/// The force laws, external fields and drag laws of the dust to cycle through,
+/// those of `forces` first, in the simulation's units.
+fn get_force_presets(
+    forces: &Forces,
+    center: Vector,
+) -> (Vec<ForceLaw>, Vec<Vec<ExternalField>>, Vec<Drag>) {
+    let mut force_laws = FORCE_LAWS.to_vec();
+    force_laws.retain(|force_law| force_law.name() != forces.law.name());
+    force_laws.insert(0, forces.law);
+
+    let mut external_field_presets = get_external_field_presets(center).to_vec();
+    external_field_presets.retain(|external_fields| *external_fields != forces.external_fields);
+    external_field_presets.insert(0, forces.external_fields.clone());
+
+    let mut drag_laws = DRAG_LAWS.to_vec();
+    drag_laws.retain(|&drag| drag != forces.dust_drag);
+    drag_laws.insert(0, forces.dust_drag);
+
+    (force_laws, external_field_presets, drag_laws)
+}
 
-    let center = Complex::new(screen_width() as f64 / 2.0, screen_height() as f64 / 2.0);
-    let initial_body_radius = Body::get_radius(INITIAL_MASS);
-    let cell_side = initial_body_radius * SQRT_2;
-    let rows_n = (screen_height() as f64 / cell_side).ceil() as usize;
-    let columns_n = (screen_width() as f64 / cell_side).ceil() as usize;
+/// A simulation per solver of `scenario`, all starting from `bodies` in its frame.
+fn new_simulations(scenario: &Scenario, bodies: &HashMap<BodyID, Body>) -> Vec<Simulation> {
+    let frame = scenario.get_frame(bodies);
 
-    let mut cells: Vec<Vec<Option<Complex<f64>>>> = vec![vec![None; columns_n]; rows_n];
+    scenario
+        .solvers
+        .iter()
+        .map(|solver| {
+            let mut simulation = Simulation::new(solver.build(), bodies.clone());
+            simulation.set_frame(frame);
+            simulation
+        })
+        .collect()
+}
 
-    for _ in 0..BODIES_N.get() {
-        'main: loop {
-            let radius = center.re() * rng.random_range(0.0..1.0).sqrt();
-            let angle = rng.random_range(0.0..2.0 * PI);
-            let pos = center
-                + Complex::new(
-                    radius * angle.cos(),
-                    center.im() / center.re() * radius * angle.sin(),
-                );
+/// Writes the scenario out, with a seed picked if it has none so that it can be rerun.
+fn convert(args: &ConvertArgs) -> Result<(), CliError> {
+    let mut scenario = args.setup.load()?;
+    scenario
+        .initial_conditions
+        .seed
+        .get_or_insert_with(|| StdRng::from_os_rng().random());
+
+    let text = toml::to_string_pretty(&scenario).map_err(CliError::Serialize)?;
+    match &args.output {
+        Some(path) => fs::write(path, text).map_err(|error| CliError::Io(path.clone(), error)),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
 
-            let i = (pos.im() / cell_side) as usize;
-            let j = (pos.re() / cell_side) as usize;
+/// How far the mouse may move, in pixels, for a press to still be a click.
+const CLICK_DISTANCE: f32 = 5.0;
+/// How far from the edge of a body, in pixels, clicking still picks it.
+const PICK_DISTANCE: f32 = 10.0;
 
-            for m in i.saturating_sub(2)..(i + 3).min(rows_n) {
-                for n in j.saturating_sub(2)..(j + 3).min(columns_n) {
-                    if let Some(cell_pos) = cells[m][n] {
-                        if (cell_pos - pos).abs() <= initial_body_radius * 2.0 {
-                            continue 'main;
-                        }
-                    }
-                }
-            }
+/// The size of the world the presets fill around the origin, in the simulation's
+/// units, which the viewer fits onto the screen whatever its resolution.
+pub const WORLD_SIZE: [f64; 2] = [1920.0, 1080.0];
 
-            cells[i][j] = Some(pos);
+pub const BORDER_THICKNESS: f32 = 2.0;
+pub const BORDER_COLOR: Color = GREEN;
+
+fn window_conf(fullscreen: bool) -> Conf {
+    Conf {
+        window_title: "gravity".to_owned(),
+        fullscreen,
+        platform: miniquad::conf::Platform {
+            linux_backend: miniquad::conf::LinuxBackend::WaylandWithX11Fallback,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
 
-            let body = Body {
-                pos,
-                speed: Complex::from_polar(INITIAL_ABS_SPEED, rng.random_range(0.0..2.0 * PI)),
-                mass: INITIAL_MASS,
-                radius: initial_body_radius,
+fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command.unwrap_or(Command::View(cli.view)) {
+        Command::View(args) => args.setup.load().and_then(|scenario| {
+            // The first bodies are made here, so that what is wrong with them is
+            // reported like the other errors
+            scenario.apply();
+            let generation = scenario
+                .initial_conditions
+                .to_simulation(&scenario.get_scales());
+            let mut rng = ChaCha12Rng::from_os_rng();
+            let seed = generation.seed.unwrap_or_else(|| rng.random());
+            let bodies = match &generation.import {
+                Some(import) => import_bodies(import, &scenario.directory, generation.density)?,
+                None => {
+                    let (initial_conditions_presets, mass_spectra) =
+                        get_presets(&generation, Vector::ZERO);
+                    generate_bodies(
+                        initial_conditions_presets[0],
+                        mass_spectra[0],
+                        &generation,
+                        seed,
+                    )
+                    .map_err(CliError::Generation)?
+                }
             };
-            bodies.insert(BodyID::now(), body);
 
-            break;
-        }
+            let fullscreen = !args.windowed;
+            macroquad::Window::from_config(
+                window_conf(fullscreen),
+                view(scenario, bodies, seed, rng, fullscreen, args.snapshot),
+            );
+            Ok(())
+        }),
+        Command::Run(args) => headless::run(&args),
+        Command::Bench(args) => headless::bench(&args),
+        Command::Convert(args) => convert(&args),
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
     }
+}
 
-    Body::adjust_momentum(&mut bodies);
+/// The options of the first Barnes-Hut solver, whether incremental and which MAC,
+/// which the keys then set for all of them.
+fn get_barnes_hut_options(simulations: &[Simulation]) -> (bool, Mac) {
+    simulations
+        .iter()
+        .find_map(|simulation| match &simulation.solver {
+            Solver::BarnesHut(barnes_hut) => Some((barnes_hut.incremental, barnes_hut.mac)),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
 
-    let mut barnes_hut_bodies = bodies.clone();
-    let mut grid_bodies = bodies.clone();
+/// Shows the simulations of `scenario` starting from `bodies`, those of its
+/// import or of its first preset drawn with `seed`.
+async fn view(
+    scenario: Scenario,
+    bodies: HashMap<BodyID, Body>,
+    mut seed: u64,
+    mut rng: ChaCha12Rng,
+    fullscreen: bool,
+    snapshot_path: PathBuf,
+) {
+    if fullscreen {
+        for _ in 0..8 {
+            set_fullscreen(true);
+            next_frame().await;
+        }
+    }
 
-    let mut direct_durations = Vec::with_capacity(MAX_AVERAGE_LENGTH.get());
-    let mut barnes_hut_durations = direct_durations.clone();
-    let mut grid_durations = direct_durations.clone();
+    let mut camera = Camera::default();
+    // Where the left button was pressed and the mouse last was, and whether it
+    // has moved too far for a click
+    let mut drag: Option<(Vec2, Vec2, bool)> = None;
+
+    // The presets are laid out around the origin, whatever the screen
+    let center = Vector::ZERO;
+    ORBIT.write().unwrap().center = center;
+
+    let generation = scenario
+        .initial_conditions
+        .to_simulation(&scenario.get_scales());
+    let (initial_conditions_presets, mass_spectra) = get_presets(&generation, center);
+    let mut initial_conditions_index = 0;
+    let mut mass_spectrum_index = 0;
+    // Until the presets are cycled through
+    let mut use_imported = generation.import.is_some();
+
+    let mut simulations = new_simulations(&scenario, &bodies);
+
+    let (mut incremental, mut mac) = get_barnes_hut_options(&simulations);
+    let mut trajectories = Vec::new();
+    for output in &scenario.outputs.trajectories {
+        match Trajectory::create(output, &scenario.directory) {
+            Ok(trajectory) => trajectories.push(trajectory),
+            Err(error) => eprintln!(
+                "{}: {}",
+                scenario.directory.join(&output.path).display(),
+                error
+            ),
+        }
+    }
+    record(&mut trajectories, &simulations);
+    let mut snapshot_status = "F5 to save, F9 to load".to_owned();
 
+    let zoom_range = scenario.constants.zoom_range.clone();
     let mut always_use_direct = false;
+    let mut auto_tau = false;
+    let mut energy_diagnostics = scenario.outputs.energy_diagnostics;
+    let forces = scenario.forces.to_simulation(&scenario.get_scales());
+    let (force_laws, external_field_presets, drag_laws) = get_force_presets(&forces, center);
+    let mut force_law_index = 0;
+    let mut drag_law_index = 0;
+    let mut external_field_index = 0;
+    // Those of the snapshot once one is loaded
+    let mut units = scenario.units;
 
     loop {
-        let mut update = false;
-        let mut new_zoom = None;
+        let mut new_zoom = match mouse_wheel().1 {
+            wheel if wheel > 0.0 => Some(camera.zoom * ZOOM_STEP),
+            wheel if wheel < 0.0 => Some(camera.zoom / ZOOM_STEP),
+            _ => None,
+        };
+
+        // Dragging pans, and clicking follows the body under the mouse
+        let mouse = Vec2::from(mouse_position());
+        if is_mouse_button_pressed(MouseButton::Left) {
+            drag = Some((mouse, mouse, false));
+        }
+        if let Some((start, last, moved)) = &mut drag {
+            *moved |= start.distance(mouse) > CLICK_DISTANCE;
+            if *moved {
+                camera.pan(mouse - *last);
+            }
+            *last = mouse;
+
+            if is_mouse_button_released(MouseButton::Left) {
+                if !*moved
+                    && let Some(body_id) = camera.pick(&simulations[0].bodies, mouse, PICK_DISTANCE)
+                {
+                    camera.follow = Follow::Body(body_id);
+                }
+                drag = None;
+            }
+        }
 
         if is_key_down(KeyCode::Minus) {
-            new_zoom = Some(Zoom {
-                zoom: zoom.zoom / ZOOM_STEP,
-            });
-            update = true;
+            new_zoom = Some(camera.zoom / ZOOM_STEP);
         } else if is_key_down(KeyCode::Equal) {
-            new_zoom = Some(Zoom {
-                zoom: zoom.zoom * ZOOM_STEP,
-            });
-            update = true;
+            new_zoom = Some(camera.zoom * ZOOM_STEP);
         } else if is_key_pressed(KeyCode::Key0) {
-            zoom.zoom = 1.0;
-            update = true;
+            camera = Camera::default();
+        } else if is_key_pressed(KeyCode::C) {
+            camera.follow = camera.follow.next();
         } else if is_key_pressed(KeyCode::Space) {
             always_use_direct = true;
-        }
-
-        if update {
-            if let Some(new_zoom) = new_zoom {
-                if ZOOM_RANGE.contains(&new_zoom.zoom) {
-                    zoom = new_zoom
+        } else if is_key_pressed(KeyCode::LeftBracket) {
+            Grid::adjust_tau(TauAdjustment::Decrease);
+            auto_tau = false;
+        } else if is_key_pressed(KeyCode::RightBracket) {
+            Grid::adjust_tau(TauAdjustment::Increase);
+            auto_tau = false;
+        } else if is_key_pressed(KeyCode::T) {
+            auto_tau = !auto_tau;
+        } else if is_key_pressed(KeyCode::G) {
+            Grid::toggle_sizing();
+        } else if is_key_pressed(KeyCode::M) {
+            mac = mac.next();
+
+            for simulation in &mut simulations {
+                if let Solver::BarnesHut(barnes_hut) = &mut simulation.solver {
+                    barnes_hut.mac = mac;
                 }
             }
+        } else if is_key_pressed(KeyCode::L) {
+            force_law_index = (force_law_index + 1) % force_laws.len();
+            *FORCE_LAW.write().unwrap() = force_laws[force_law_index];
 
-            camera.zoom = vec2(
-                2.0 / screen_width() * zoom.zoom,
-                2.0 / screen_height() * zoom.zoom,
-            );
+            // The potential is measured from another zero
+            for simulation in &mut simulations {
+                simulation.reset_energy();
+            }
+        } else if is_key_pressed(KeyCode::X) {
+            external_field_index = (external_field_index + 1) % external_field_presets.len();
+            *EXTERNAL_FIELDS.write().unwrap() =
+                external_field_presets[external_field_index].clone();
 
-            set_camera(&camera);
-        }
+            for simulation in &mut simulations {
+                simulation.reset_energy();
+            }
+        } else if is_key_pressed(KeyCode::D) {
+            let mut medium = MEDIUM.write().unwrap();
+            *medium = match *medium {
+                Some(_) => None,
+                None => Some(forces.medium.unwrap_or_else(|| get_medium_preset(center))),
+            };
+        } else if is_key_pressed(KeyCode::K) {
+            drag_law_index = (drag_law_index + 1) % drag_laws.len();
+            SPECIES.write().unwrap()[DUST].drag = Some(drag_laws[drag_law_index]);
+        } else if is_key_pressed(KeyCode::E) {
+            energy_diagnostics = !energy_diagnostics;
+
+            for simulation in &mut simulations {
+                simulation.reset_energy();
+            }
+        } else if is_key_pressed(KeyCode::F) {
+            // That of the scenario, or else the one in which the bar, if any, stands still
+            let frame = match simulations[0].frame {
+                Some(_) => None,
+                None => Some(
+                    scenario
+                        .frame
+                        .map_or(FrameConfig::Corotating, |frame| {
+                            frame.to_simulation(&scenario.get_scales())
+                        })
+                        .build(&simulations[0].bodies, simulations[0].time),
+                ),
+            };
 
-        // Direct
-        Body::update_bodies(DT, &mut bodies);
-        //Body::adjust_momentum(&mut bodies);
+            for simulation in &mut simulations {
+                simulation.set_frame(frame);
+            }
+        } else if is_key_pressed(KeyCode::P) {
+            toggle_post_newtonian(forces.speed_of_light.unwrap_or(DEFAULT_SPEED_OF_LIGHT));
+        } else if is_key_pressed(KeyCode::Comma) {
+            adjust_speed_of_light(SpeedOfLightAdjustment::Decrease);
+        } else if is_key_pressed(KeyCode::Period) {
+            adjust_speed_of_light(SpeedOfLightAdjustment::Increase);
+        } else if is_key_pressed(KeyCode::N) || is_key_pressed(KeyCode::B) {
+            if is_key_pressed(KeyCode::N) {
+                // The imported bodies give way to the first preset
+                if !use_imported {
+                    initial_conditions_index =
+                        (initial_conditions_index + 1) % initial_conditions_presets.len();
+                }
+            } else {
+                mass_spectrum_index = (mass_spectrum_index + 1) % mass_spectra.len();
+            }
+            use_imported = false;
+            seed = rng.random();
+            // A preset too crowded for the bodies is skipped, the others carrying on
+            match generate_bodies(
+                initial_conditions_presets[initial_conditions_index],
+                mass_spectra[mass_spectrum_index],
+                &generation,
+                seed,
+            ) {
+                Ok(bodies) => {
+                    let frame = scenario.get_frame(&bodies);
+                    for simulation in &mut simulations {
+                        simulation.reset(bodies.clone());
+                        simulation.set_frame(frame);
+                    }
+                    stop_recording(&mut trajectories);
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        } else if is_key_pressed(KeyCode::I) {
+            incremental = !incremental;
 
-        let duration_direct = Direct::handle(&mut bodies).as_nanos() as f64 / bodies.len() as f64;
-        if direct_durations.len() == MAX_AVERAGE_LENGTH.get() {
-            direct_durations.clear();
+            for simulation in &mut simulations {
+                if let Solver::BarnesHut(barnes_hut) = &mut simulation.solver {
+                    barnes_hut.incremental = incremental;
+                }
+            }
+        } else if is_key_pressed(KeyCode::F5) {
+            snapshot_status = match snapshot::save(&snapshot_path, &simulations, &units, seed, &rng)
+            {
+                Ok(()) => format!(
+                    "saved to {} at step {}",
+                    snapshot_path.display(),
+                    simulations[0].steps
+                ),
+                Err(error) => format!("cannot save to {}: {}", snapshot_path.display(), error),
+            };
+        } else if is_key_pressed(KeyCode::F9) {
+            snapshot_status = match Snapshot::load(&snapshot_path) {
+                Ok(snapshot) => {
+                    snapshot.apply();
+                    simulations = snapshot.simulations;
+                    stop_recording(&mut trajectories);
+                    seed = snapshot.seed;
+                    rng = snapshot.rng;
+                    units = snapshot.units;
+                    (incremental, mac) = get_barnes_hut_options(&simulations);
+
+                    // So that cycling carries on from the restored models
+                    force_law_index = force_laws
+                        .iter()
+                        .position(|force_law| force_law.name() == snapshot.forces.law.name())
+                        .unwrap_or(0);
+                    drag_law_index = drag_laws
+                        .iter()
+                        .position(|&drag_law| drag_law == snapshot.forces.dust_drag)
+                        .unwrap_or(0);
+                    external_field_index = external_field_presets
+                        .iter()
+                        .position(|external_fields| {
+                            *external_fields == snapshot.forces.external_fields
+                        })
+                        .unwrap_or(0);
+
+                    format!(
+                        "loaded from {} at step {}",
+                        snapshot_path.display(),
+                        simulations[0].steps
+                    )
+                }
+                Err(error) => format!("cannot load {}: {}", snapshot_path.display(), error),
+            };
         }
-        direct_durations.push(duration_direct);
 
-        let direct_average = direct_durations.iter().sum::<f64>() / direct_durations.len() as f64;
+        if cfg!(feature = "3d") {
+            let mut orbit = ORBIT.write().unwrap();
 
-        // Barnes-Hut
-        Body::update_bodies(DT, &mut barnes_hut_bodies);
-        Body::adjust_momentum(&mut barnes_hut_bodies);
+            if is_key_down(KeyCode::Left) {
+                orbit.yaw -= ORBIT_STEP;
+            } else if is_key_down(KeyCode::Right) {
+                orbit.yaw += ORBIT_STEP;
+            }
 
-        let duration_barnes_hut = if always_use_direct {
-            Direct::handle(&mut barnes_hut_bodies)
-        } else {
-            BarnesHut::handle(&mut barnes_hut_bodies, &zoom)
+            if is_key_down(KeyCode::Up) {
+                orbit.pitch -= ORBIT_STEP;
+            } else if is_key_down(KeyCode::Down) {
+                orbit.pitch += ORBIT_STEP;
+            }
         }
-        .as_nanos() as f64
-            / barnes_hut_bodies.len() as f64;
 
-        if barnes_hut_durations.len() == MAX_AVERAGE_LENGTH.get() {
-            barnes_hut_durations.clear();
+        // Fitting the bodies may have left the range, which zooming back towards is allowed
+        let in_range = camera.zoom.clamp(zoom_range.start, zoom_range.end);
+        if let Some(new_zoom) = new_zoom
+            && (zoom_range.contains(&new_zoom)
+                || (new_zoom - in_range).abs() < (camera.zoom - in_range).abs())
+        {
+            camera.zoom = new_zoom;
+            // Fitting the bodies would undo it
+            if camera.follow == Follow::Everything {
+                camera.follow = Follow::Nothing;
+            }
         }
-        barnes_hut_durations.push(duration_barnes_hut);
 
-        let barnes_hut_average =
-            barnes_hut_durations.iter().sum::<f64>() / barnes_hut_durations.len() as f64;
+        // For the trees the solvers draw while stepping
+        camera.set();
 
-        // Grid
-        Body::update_bodies(DT, &mut grid_bodies);
-        Body::adjust_momentum(&mut grid_bodies);
+        for simulation in &mut simulations {
+            simulation.step(DT.get(), always_use_direct, &camera);
 
-        let duration_grid = if always_use_direct {
-            Direct::handle(&mut grid_bodies)
-        } else {
-            Grid::handle(&mut grid_bodies, &zoom)
+            if energy_diagnostics {
+                simulation.measure_energy();
+            }
         }
-        .as_nanos() as f64
-            / grid_bodies.len() as f64;
 
-        if grid_durations.len() == MAX_AVERAGE_LENGTH.get() {
-            grid_durations.clear();
+        // After the step, so that the bodies are drawn where they are followed to,
+        // and every frame, as the window may be resized
+        camera.update(&simulations[0].bodies);
+        camera.set();
+
+        record(&mut trajectories, &simulations);
+
+        // Scenarios may leave either solver out
+        let duration_barnes_hut = simulations
+            .iter()
+            .find(|simulation| matches!(simulation.solver, Solver::BarnesHut(_)))
+            .map(|simulation| simulation.duration);
+        let duration_grid = simulations
+            .iter()
+            .find(|simulation| matches!(simulation.solver, Solver::Grid))
+            .map(|simulation| simulation.duration);
+
+        if !always_use_direct
+            && auto_tau
+            && let Some(duration_grid) = duration_grid
+        {
+            Grid::tune_tau(duration_grid);
         }
-        grid_durations.push(duration_grid);
-
-        let grid_average = grid_durations.iter().sum::<f64>() / grid_durations.len() as f64;
 
-        if !always_use_direct {
+        if !always_use_direct
+            && let (Some(duration_barnes_hut), Some(duration_grid)) =
+                (duration_barnes_hut, duration_grid)
+        {
             BarnesHut::adjust_theta(if duration_barnes_hut <= duration_grid {
                 ThetaAdjustment::Decrease
             } else {
@@ -208,71 +764,156 @@ async fn main() {
             });
         }
 
-        for (hashmap, color) in [
-            (&grid_bodies, Grid::COLOR),
-            (&barnes_hut_bodies, BarnesHut::COLOR),
-            (&bodies, Direct::COLOR),
-        ] {
-            for body in hashmap.values() {
-                draw_circle(
-                    body.pos.re() as f32,
-                    body.pos.im() as f32,
-                    body.radius as f32,
-                    color,
-                );
+        let orbit = *ORBIT.read().unwrap();
+        for simulation in simulations.iter().rev() {
+            for body in simulation.bodies.values() {
+                let pos = orbit.project(body.pos);
+
+                draw_circle(pos.x, pos.y, body.radius as f32, simulation.solver.color());
             }
         }
 
-        let rect = zoom.get_rect();
+        let rect = camera.get_rect();
         let mut measured = None;
-        for (index, (name, color, average)) in [
-            ("Direct", Direct::COLOR, direct_average),
-            ("Barnes-Hut", BarnesHut::COLOR, barnes_hut_average),
-            ("Grid", Grid::COLOR, grid_average),
-        ]
-        .iter()
-        .enumerate()
-        {
+        for (index, simulation) in simulations.iter().enumerate() {
+            let average = simulation.get_average();
+
             if measured.is_none() {
-                measured = Some(measure_text(
-                    &direct_average.to_string(),
-                    None,
-                    FONT_SIZE,
-                    1.0,
-                ));
+                measured = Some(measure_text(&average.to_string(), None, FONT_SIZE, 1.0));
+            }
+
+            let mut text = format!("{}: {}", simulation.solver.name(), average as usize);
+            if let Solver::BarnesHut(barnes_hut) = &simulation.solver
+                && barnes_hut.incremental
+            {
+                text += &format!(", rebuilds: {}/{}", barnes_hut.rebuilds_n, simulation.steps);
+            }
+            if let (Some(energy), Some(drift)) = (simulation.energy, simulation.get_energy_drift())
+            {
+                text += &format!(
+                    ", {}: {:.3e} (K: {:.3e}, U: {:.3e}, drift: {:+.2e})",
+                    if simulation.frame.is_some() {
+                        "E_J"
+                    } else {
+                        "E"
+                    },
+                    energy.get_total(),
+                    energy.kinetic,
+                    energy.potential,
+                    drift
+                );
             }
 
             draw_text_ex(
-                &format!("{}: {}", name, *average as usize),
-                rect.top_left.re() as f32,
-                rect.top_left.im() as f32
-                    + measured.unwrap().height * (index + 1) as f32 / zoom.zoom,
+                &text,
+                rect.x,
+                rect.y + measured.unwrap().height * (index + 1) as f32 * camera.get_scale(),
                 TextParams {
                     font: None,
                     font_size: FONT_SIZE,
-                    font_scale: 1.0 / zoom.zoom,
+                    font_scale: camera.get_scale(),
                     font_scale_aspect: 1.0,
                     rotation: 0.0,
-                    color: *color,
+                    color: simulation.solver.color(),
                 },
             );
         }
 
-        let text = &format!("Always use direct: {}", always_use_direct);
-        let measured = measure_text(text, None, FONT_SIZE, 1.0);
-        draw_text_ex(
-            text,
-            rect.bottom_right.re() as f32 - measured.width / zoom.zoom,
-            rect.top_left.im() as f32 + measured.height / zoom.zoom,
-            TextParams {
-                font: None,
-                font_size: FONT_SIZE,
-                font_scale: 1.0 / zoom.zoom,
-                font_scale_aspect: 1.0,
-                rotation: 0.0,
-                color: WHITE,
+        for (index, text) in [
+            match &generation.import {
+                Some(import) if use_imported => {
+                    format!("Initial conditions: {}", import.path.display())
+                }
+                _ => format!(
+                    "Initial conditions: {} (seed {})",
+                    initial_conditions_presets[initial_conditions_index].name(),
+                    seed
+                ),
             },
-        );
+            if use_imported {
+                "Masses: Imported".to_owned()
+            } else {
+                format!("Masses: {}", mass_spectra[mass_spectrum_index].name())
+            },
+            format!("Always use direct: {}", always_use_direct),
+            format!(
+                "TAU: {:.2} ({}{})",
+                *TAU.read().unwrap(),
+                SIZING.read().unwrap().name(),
+                if auto_tau { ", auto" } else { "" }
+            ),
+            format!("Incremental tree: {}", incremental),
+            if mac.for_pairs() != mac
+                && simulations.iter().any(|simulation| {
+                    matches!(&simulation.solver, Solver::BarnesHut(barnes_hut)
+                        if barnes_hut.traversal == Traversal::DualTree)
+                })
+            {
+                format!(
+                    "MAC: {} ({} for the dual tree)",
+                    mac.name(),
+                    mac.for_pairs().name()
+                )
+            } else {
+                format!("MAC: {}", mac.name())
+            },
+            format!("Force law: {}", FORCE_LAW.read().unwrap().name()),
+            match *MEDIUM.read().unwrap() {
+                Some(_) => format!(
+                    "Drag: {} ({})",
+                    SPECIES.read().unwrap()[DUST].name,
+                    drag_laws[drag_law_index].name()
+                ),
+                None => "Drag: off".to_owned(),
+            },
+            format!(
+                "External field: {}",
+                match EXTERNAL_FIELDS.read().unwrap().as_slice() {
+                    [] => "None".to_owned(),
+                    external_fields => external_fields
+                        .iter()
+                        .map(|external_field| external_field.name())
+                        .collect::<Vec<_>>()
+                        .join(" + "),
+                }
+            ),
+            match *SPEED_OF_LIGHT.read().unwrap() {
+                Some(c) => format!("1PN (Direct): c = {:.2}", c),
+                None => "1PN (Direct): off".to_owned(),
+            },
+            match simulations[0].frame {
+                Some(frame) => format!("Frame: Rotating (Ω = {:.2e})", frame.angular_speed),
+                None => "Frame: Inertial".to_owned(),
+            },
+            format!(
+                "Time: {:.3e} ({})",
+                SCALES
+                    .read()
+                    .unwrap()
+                    .in_scenario(Quantity::Time, simulations[0].time),
+                units.system.name()
+            ),
+            format!("Following: {}", camera.follow.name()),
+            format!("Snapshot: {}", snapshot_status),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let measured = measure_text(text, None, FONT_SIZE, 1.0);
+            draw_text_ex(
+                text,
+                rect.right() - measured.width * camera.get_scale(),
+                rect.y + measured.height * (index + 1) as f32 * camera.get_scale(),
+                TextParams {
+                    font: None,
+                    font_size: FONT_SIZE,
+                    font_scale: camera.get_scale(),
+                    font_scale_aspect: 1.0,
+                    rotation: 0.0,
+                    color: WHITE,
+                },
+            );
+        }
 
         next_frame().await;
     }
@@ -0,0 +1,236 @@
+use crate::{
+    BORDER_COLOR, BORDER_THICKNESS, Body, BodyID, Camera,
+    barnes_hut::Rectangle,
+    body::get_rectangle,
+    force_law::{ForceLaw, Sources},
+    grid::{TAU, get_cell_index},
+    orbit::draw_box,
+    vector::{DIMENSIONS, Vector, VectorExt},
+};
+use macroquad::prelude::*;
+use std::{
+    array::from_fn,
+    collections::HashMap,
+    sync::{LazyLock, RwLock},
+    time::{Duration, Instant},
+};
+
+pub type LevelID = usize;
+
+/// A cell holding more bodies than this gets refined into a nested level.
+const MAX_CELL_BODIES: usize = 16;
+/// The number of cells a refined cell is split into along every axis.
+const REFINEMENT: usize = 4;
+const MAX_DEPTH: usize = 8;
+/// Whether to draw the cells of every level.
+pub static DRAW: LazyLock<RwLock<bool>> = LazyLock::new(|| RwLock::new(false));
+
+#[derive(Clone)]
+pub struct MultiGridCell {
+    pub rectangle: Rectangle,
+    pub bodies: Vec<BodyID>,
+    pub total_mass: f64,
+    pub pos: Vector,
+    pub sources: Sources,
+    pub level: Option<LevelID>,
+}
+
+impl MultiGridCell {
+    /// Whether `pos` lies within one cell size of the cell, in which case
+    /// its centre of mass is too coarse to stand in for its bodies.
+    pub fn is_near(&self, pos: Vector) -> bool {
+        let top_left = self.rectangle.top_left.components();
+        let bottom_right = self.rectangle.bottom_right.components();
+
+        pos.components().iter().enumerate().all(|(k, component)| {
+            let size = bottom_right[k] - top_left[k];
+            (top_left[k] - size..=bottom_right[k] + size).contains(component)
+        })
+    }
+}
+
+pub struct Level {
+    pub depth: usize,
+    /// Ordered by the first axis first.
+    pub cells: Vec<MultiGridCell>,
+}
+
+impl Level {
+    pub fn new(
+        depth: usize,
+        rectangle: &Rectangle,
+        counts: [usize; DIMENSIONS],
+        body_ids: &[BodyID],
+        bodies: &HashMap<BodyID, Body>,
+        force_law: ForceLaw,
+    ) -> Self {
+        let min = rectangle.top_left.components();
+        let max = rectangle.bottom_right.components();
+
+        let edges: [Vec<f64>; DIMENSIONS] = from_fn(|k| {
+            (0..=counts[k])
+                .map(|i| min[k] + (max[k] - min[k]) * i as f64 / counts[k] as f64)
+                .collect()
+        });
+        let strides: [usize; DIMENSIONS] = from_fn(|k| counts[..k].iter().product());
+
+        let cells_n = counts.iter().product();
+        let mut cells = Vec::with_capacity(cells_n);
+        for flat_index in 0..cells_n {
+            let indices: [usize; DIMENSIONS] = from_fn(|k| flat_index / strides[k] % counts[k]);
+
+            cells.push(MultiGridCell {
+                rectangle: Rectangle {
+                    top_left: Vector::from_components(from_fn(|k| edges[k][indices[k]])),
+                    bottom_right: Vector::from_components(from_fn(|k| edges[k][indices[k] + 1])),
+                },
+                bodies: Vec::new(),
+                total_mass: 0.0,
+                pos: Vector::ZERO,
+                sources: Sources::default(),
+                level: None,
+            });
+        }
+
+        for body_id in body_ids {
+            let body = bodies.get(body_id).unwrap();
+            let components = body.pos.components();
+
+            let cell = &mut cells[(0..DIMENSIONS)
+                .map(|k| get_cell_index(&edges[k], components[k]) * strides[k])
+                .sum::<usize>()];
+
+            cell.bodies.push(*body_id);
+            cell.total_mass += body.mass;
+            cell.pos += body.mass * body.pos;
+            cell.sources.add(body.pos, force_law.get_source(body));
+        }
+
+        for cell in &mut cells {
+            if cell.total_mass != 0.0 {
+                cell.pos /= cell.total_mass;
+            }
+            cell.sources.finish();
+        }
+
+        Self { depth, cells }
+    }
+}
+
+/// A hierarchy of uniform grids: cells that are too crowded are refined into
+/// nested levels, so a dense core and a sparse halo each get a fitting cell size.
+pub struct MultiGrid;
+
+impl MultiGrid {
+    pub const COLOR: Color = ORANGE;
+
+    pub fn build(bodies: &HashMap<BodyID, Body>, force_law: ForceLaw) -> Vec<Level> {
+        let rectangle = get_rectangle(bodies);
+        let size = (rectangle.bottom_right - rectangle.top_left).components();
+
+        let target_size = ((*TAU.read().unwrap() * size.iter().product::<f64>())
+            / (bodies.len() as f64).sqrt())
+        .powf(1.0 / DIMENSIONS as f64);
+
+        let mut levels = vec![Level::new(
+            0,
+            &rectangle,
+            size.map(|size| ((size / target_size).round() as usize).max(1)),
+            &bodies.keys().cloned().collect::<Vec<_>>(),
+            bodies,
+            force_law,
+        )];
+
+        let mut level_id = 0;
+        while level_id < levels.len() {
+            if levels[level_id].depth < MAX_DEPTH {
+                for cell_index in 0..levels[level_id].cells.len() {
+                    let cell = &levels[level_id].cells[cell_index];
+
+                    if cell.bodies.len() > MAX_CELL_BODIES {
+                        let level = Level::new(
+                            levels[level_id].depth + 1,
+                            &cell.rectangle,
+                            [REFINEMENT; DIMENSIONS],
+                            &cell.bodies,
+                            bodies,
+                            force_law,
+                        );
+
+                        levels[level_id].cells[cell_index].level = Some(levels.len());
+                        levels.push(level);
+                    }
+                }
+            }
+
+            level_id += 1;
+        }
+
+        levels
+    }
+
+    pub fn handle(
+        bodies: &mut HashMap<BodyID, Body>,
+        force_law: ForceLaw,
+        camera: &Camera,
+    ) -> Duration {
+        let start = Instant::now();
+
+        let levels = Self::build(bodies, force_law);
+
+        let bodies_clone = bodies.clone();
+        let mut stack = Vec::with_capacity(MAX_DEPTH * REFINEMENT.pow(DIMENSIONS as u32));
+
+        for (body_id, body) in bodies.iter_mut() {
+            stack.push(0);
+
+            while let Some(level_id) = stack.pop() {
+                for cell in &levels[level_id].cells {
+                    if cell.total_mass == 0.0 {
+                        continue;
+                    }
+
+                    if cell.is_near(body.pos) {
+                        match cell.level {
+                            Some(level_id) => stack.push(level_id),
+                            None => {
+                                for rhs_body_id in &cell.bodies {
+                                    if body_id != rhs_body_id {
+                                        let rhs_body = bodies_clone.get(rhs_body_id).unwrap();
+
+                                        body.adjust_speed(
+                                            rhs_body.pos,
+                                            force_law.get_source(rhs_body),
+                                            force_law,
+                                        )
+                                    }
+                                }
+                            }
+                        }
+                    } else {
+                        for monopole in cell.sources.get_monopoles() {
+                            body.adjust_speed(monopole.pos, monopole.source, force_law)
+                        }
+                    }
+                }
+            }
+        }
+
+        let end = start.elapsed();
+
+        if *DRAW.read().unwrap() {
+            let border = BORDER_THICKNESS * camera.get_scale();
+
+            for cell in levels.iter().flat_map(|level| &level.cells) {
+                draw_box(
+                    cell.rectangle.top_left,
+                    cell.rectangle.bottom_right - cell.rectangle.top_left,
+                    border,
+                    BORDER_COLOR,
+                );
+            }
+        }
+
+        end
+    }
+}
@@ -0,0 +1,57 @@
+use crate::vector::{DIMENSIONS, ORTHANTS_N, Vector, VectorExt};
+use macroquad::prelude::*;
+use std::sync::{LazyLock, RwLock};
+
+pub const ORBIT_STEP: f64 = 0.03;
+
+pub static ORBIT: LazyLock<RwLock<Orbit>> = LazyLock::new(|| {
+    RwLock::new(Orbit {
+        yaw: 0.0,
+        pitch: 0.0,
+        center: Vector::splat(0.0),
+    })
+});
+
+/// The view of the 3D world, rotated about `center` and projected onto the
+/// screen plane. In 2D the projection keeps positions as they are.
+#[derive(Clone, Copy, Debug)]
+pub struct Orbit {
+    pub yaw: f64,
+    pub pitch: f64,
+    pub center: Vector,
+}
+
+impl Orbit {
+    #[cfg(not(feature = "3d"))]
+    pub fn project(&self, pos: Vector) -> Vec2 {
+        vec2(pos.x() as f32, pos.y() as f32)
+    }
+
+    #[cfg(feature = "3d")]
+    pub fn project(&self, pos: Vector) -> Vec2 {
+        let d = pos - self.center;
+
+        let x = d.x * self.yaw.cos() - d.z * self.yaw.sin();
+        let z = d.x * self.yaw.sin() + d.z * self.yaw.cos();
+        let y = d.y * self.pitch.cos() - z * self.pitch.sin();
+
+        vec2((self.center.x + x) as f32, (self.center.y + y) as f32)
+    }
+}
+
+/// Draws the edges of the box at `corner`, projected with `ORBIT`.
+pub fn draw_box(corner: Vector, size: Vector, thickness: f32, color: Color) {
+    let orbit = *ORBIT.read().unwrap();
+    let get_corner = |orthant: usize| orbit.project(corner.get_orthant(orthant, size));
+
+    for orthant in 0..ORTHANTS_N {
+        for k in 0..DIMENSIONS {
+            if orthant >> k & 1 == 0 {
+                let start = get_corner(orthant);
+                let end = get_corner(orthant | 1 << k);
+
+                draw_line(start.x, start.y, end.x, end.y, thickness, color);
+            }
+        }
+    }
+}
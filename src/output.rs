@@ -0,0 +1,327 @@
+use crate::{
+    solver::Simulation,
+    units::{Quantity, SCALES},
+    vector::{DIMENSIONS, VectorExt},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    num::NonZero,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_INTERVAL: NonZero<usize> = NonZero::new(10).unwrap();
+
+const BINARY_MAGIC: &[u8; 8] = b"GRAVTRAJ";
+const BINARY_VERSION: u32 = 1;
+
+/// The headers of the columns are this long, with the shape padded with spaces,
+/// so that they can be rewritten in place as the columns grow.
+const NPY_HEADER_LENGTH: usize = 128;
+/// The width of the solver names in the columnar files, longer names being cut.
+const SOLVER_NAME_LENGTH: usize = 32;
+
+const COMPONENT_NAMES: [&str; 3] = ["x", "y", "z"];
+
+/// How the body states are written.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrajectoryFormat {
+    /// A row per body per record, with a header.
+    Csv,
+    /// Little-endian records: the magic and the version, the number of dimensions,
+    /// then per record the solver name (its length as a byte, then UTF-8), the time,
+    /// the step and the number of bodies, each with its ID, position, speed, mass and
+    /// radius, all but the ID in single precision.
+    Binary,
+    /// A directory with a NumPy `.npy` file per column, a row per body per record,
+    /// which `numpy.load` reads and pandas can put together into a table.
+    Columnar,
+}
+
+/// A file to stream the bodies of every simulation to, in the scenario's units.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrajectoryOutput {
+    pub format: TrajectoryFormat,
+    /// A file, or a directory for `Columnar`, relative to the scenario.
+    pub path: PathBuf,
+    /// Records the bodies every this many steps, starting from the initial ones.
+    #[serde(default = "get_default_interval")]
+    pub interval: NonZero<usize>,
+}
+
+fn get_default_interval() -> NonZero<usize> {
+    DEFAULT_INTERVAL
+}
+
+impl TrajectoryFormat {
+    /// `Csv` for `.csv` files, `Binary` for `.bin` files and `Columnar` otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => Self::Csv,
+            Some("bin") => Self::Binary,
+            _ => Self::Columnar,
+        }
+    }
+}
+
+/// A NumPy array being appended to.
+struct Column {
+    writer: BufWriter<File>,
+    /// The NumPy type, such as `<f8`.
+    descr: String,
+    len: usize,
+    /// The length the header was last written with.
+    header_len: usize,
+}
+
+impl Column {
+    fn create(path: &Path, descr: String) -> io::Result<Self> {
+        let mut column = Self {
+            writer: BufWriter::new(File::create(path)?),
+            descr,
+            len: 0,
+            header_len: 0,
+        };
+        column.write_header()?;
+
+        Ok(column)
+    }
+
+    /// The version 1.0 header, padded to `NPY_HEADER_LENGTH`.
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}",
+            self.descr, self.len
+        );
+        // The magic, the version and the length of the header take 10 bytes
+        let padding = NPY_HEADER_LENGTH - 10 - header.len() - 1;
+        header.extend(std::iter::repeat_n(' ', padding));
+        header.push('\n');
+
+        self.header_len = self.len;
+        self.writer.write_all(b"\x93NUMPY\x01\x00")?;
+        self.writer
+            .write_all(&(header.len() as u16).to_le_bytes())?;
+        self.writer.write_all(header.as_bytes())
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.len += 1;
+        self.writer.write_all(bytes)
+    }
+
+    /// Brings the shape in the header up to date if rows were appended, so that
+    /// the column can be read even if nothing more gets written.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.len == self.header_len {
+            return Ok(());
+        }
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+/// The columns of a `Columnar` trajectory.
+struct Columns {
+    time: Column,
+    step: Column,
+    solver: Column,
+    id: Column,
+    pos: [Column; DIMENSIONS],
+    speed: [Column; DIMENSIONS],
+    mass: Column,
+    radius: Column,
+}
+
+impl Columns {
+    fn create(directory: &Path) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let column = |name: &str, descr: &str| {
+            Column::create(&directory.join(format!("{}.npy", name)), descr.to_owned())
+        };
+        let components = |prefix: &str| -> io::Result<[Column; DIMENSIONS]> {
+            let mut columns = Vec::with_capacity(DIMENSIONS);
+            for name in &COMPONENT_NAMES[..DIMENSIONS] {
+                columns.push(column(&format!("{}{}", prefix, name), "<f8")?);
+            }
+            Ok(columns.try_into().ok().unwrap())
+        };
+
+        Ok(Self {
+            time: column("time", "<f8")?,
+            step: column("step", "<u8")?,
+            solver: column("solver", &format!("|S{}", SOLVER_NAME_LENGTH))?,
+            id: column("id", "<u8")?,
+            pos: components("")?,
+            speed: components("v")?,
+            mass: column("mass", "<f8")?,
+            radius: column("radius", "<f8")?,
+        })
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Column> {
+        [
+            &mut self.time,
+            &mut self.step,
+            &mut self.solver,
+            &mut self.id,
+            &mut self.mass,
+            &mut self.radius,
+        ]
+        .into_iter()
+        .chain(&mut self.pos)
+        .chain(&mut self.speed)
+    }
+}
+
+enum TrajectoryWriter {
+    Csv(BufWriter<File>),
+    Binary(BufWriter<File>),
+    Columnar(Box<Columns>),
+}
+
+/// An output being streamed to, one record of every simulation at a time.
+pub struct Trajectory {
+    pub path: PathBuf,
+    interval: NonZero<usize>,
+    writer: TrajectoryWriter,
+}
+
+impl Trajectory {
+    /// Starts `output`, its path being relative to `directory`.
+    pub fn create(output: &TrajectoryOutput, directory: &Path) -> io::Result<Self> {
+        let path = directory.join(&output.path);
+        let writer = match output.format {
+            TrajectoryFormat::Csv => {
+                let mut writer = BufWriter::new(File::create(&path)?);
+                let names = &COMPONENT_NAMES[..DIMENSIONS];
+                writeln!(
+                    writer,
+                    "time,step,solver,id,{},{},mass,radius",
+                    names.join(","),
+                    names
+                        .iter()
+                        .map(|name| format!("v{}", name))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )?;
+                TrajectoryWriter::Csv(writer)
+            }
+            TrajectoryFormat::Binary => {
+                let mut writer = BufWriter::new(File::create(&path)?);
+                writer.write_all(BINARY_MAGIC)?;
+                writer.write_all(&BINARY_VERSION.to_le_bytes())?;
+                writer.write_all(&[DIMENSIONS as u8])?;
+                TrajectoryWriter::Binary(writer)
+            }
+            TrajectoryFormat::Columnar => {
+                TrajectoryWriter::Columnar(Box::new(Columns::create(&path)?))
+            }
+        };
+
+        Ok(Self {
+            path,
+            interval: output.interval,
+            writer,
+        })
+    }
+
+    /// Records the bodies of `simulations` if they are at a step that is due,
+    /// in the order they were created in.
+    pub fn record(&mut self, simulations: &[Simulation]) -> io::Result<()> {
+        let is_due =
+            |simulation: &Simulation| simulation.steps.is_multiple_of(self.interval.get() as u64);
+        if !simulations.iter().any(is_due) {
+            return Ok(());
+        }
+        let scales = *SCALES.read().unwrap();
+
+        for simulation in simulations.iter().filter(|simulation| is_due(simulation)) {
+            let time = scales.in_scenario(Quantity::Time, simulation.time);
+
+            let name = simulation.solver.name();
+            let mut body_ids = simulation.bodies.keys().copied().collect::<Vec<_>>();
+            body_ids.sort();
+
+            if let TrajectoryWriter::Binary(writer) = &mut self.writer {
+                writer.write_all(&[name.len() as u8])?;
+                writer.write_all(name.as_bytes())?;
+                writer.write_all(&time.to_le_bytes())?;
+                writer.write_all(&simulation.steps.to_le_bytes())?;
+                writer.write_all(&(body_ids.len() as u64).to_le_bytes())?;
+            }
+
+            for body_id in body_ids {
+                let body = &simulation.bodies[&body_id];
+                let pos = body
+                    .pos
+                    .components()
+                    .map(|component| scales.in_scenario(Quantity::Length, component));
+                let speed = body
+                    .speed
+                    .components()
+                    .map(|component| scales.in_scenario(Quantity::Speed, component));
+                let mass = scales.in_scenario(Quantity::Mass, body.mass);
+                let radius = scales.in_scenario(Quantity::Length, body.radius);
+
+                match &mut self.writer {
+                    TrajectoryWriter::Csv(writer) => {
+                        let join = |components: [f64; DIMENSIONS]| {
+                            components.map(|component| component.to_string()).join(",")
+                        };
+                        writeln!(
+                            writer,
+                            "{},{},{},{},{},{},{},{}",
+                            time,
+                            simulation.steps,
+                            name,
+                            body_id,
+                            join(pos),
+                            join(speed),
+                            mass,
+                            radius
+                        )?;
+                    }
+                    TrajectoryWriter::Binary(writer) => {
+                        writer.write_all(&body_id.to_le_bytes())?;
+                        for component in pos.into_iter().chain(speed) {
+                            writer.write_all(&(component as f32).to_le_bytes())?;
+                        }
+                        writer.write_all(&(mass as f32).to_le_bytes())?;
+                        writer.write_all(&(radius as f32).to_le_bytes())?;
+                    }
+                    TrajectoryWriter::Columnar(columns) => {
+                        let mut solver = [0; SOLVER_NAME_LENGTH];
+                        let length = name.len().min(SOLVER_NAME_LENGTH);
+                        solver[..length].copy_from_slice(&name.as_bytes()[..length]);
+
+                        columns.time.push(&time.to_le_bytes())?;
+                        columns.step.push(&simulation.steps.to_le_bytes())?;
+                        columns.solver.push(&solver)?;
+                        columns.id.push(&body_id.to_le_bytes())?;
+                        for (column, component) in columns.pos.iter_mut().zip(pos) {
+                            column.push(&component.to_le_bytes())?;
+                        }
+                        for (column, component) in columns.speed.iter_mut().zip(speed) {
+                            column.push(&component.to_le_bytes())?;
+                        }
+                        columns.mass.push(&mass.to_le_bytes())?;
+                        columns.radius.push(&radius.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+
+        // The viewer may be closed at any time
+        match &mut self.writer {
+            TrajectoryWriter::Csv(writer) | TrajectoryWriter::Binary(writer) => writer.flush(),
+            TrajectoryWriter::Columnar(columns) => columns.iter_mut().try_for_each(Column::flush),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+#[cfg(not(feature = "3d"))]
+use crate::vector::VectorExt;
+use crate::{
+    body::{Body, G},
+    vector::Vector,
+};
+use std::{
+    ops::RangeInclusive,
+    sync::{LazyLock, RwLock},
+};
+
+/// The speed of light for the first post-Newtonian corrections,
+/// or `None` for Newtonian dynamics.
+pub static SPEED_OF_LIGHT: LazyLock<RwLock<Option<f64>>> = LazyLock::new(|| RwLock::new(None));
+/// Far below the real one, so that the corrections show at the speeds of the simulation.
+pub const DEFAULT_SPEED_OF_LIGHT: f64 = 5.0;
+const SPEED_OF_LIGHT_STEP: f64 = 1.25;
+const SPEED_OF_LIGHT_RANGE: RangeInclusive<f64> = 0.1..=1000.0;
+
+#[derive(Clone, Copy)]
+pub enum SpeedOfLightAdjustment {
+    Increase,
+    Decrease,
+}
+
+/// Turns the corrections off, or on at `speed_of_light`.
+pub fn toggle_post_newtonian(speed_of_light: f64) {
+    let mut write = SPEED_OF_LIGHT.write().unwrap();
+    *write = match *write {
+        Some(_) => None,
+        None => Some(speed_of_light),
+    };
+}
+
+pub fn adjust_speed_of_light(adjustment: SpeedOfLightAdjustment) {
+    if let Some(c) = SPEED_OF_LIGHT.write().unwrap().as_mut() {
+        *c = match adjustment {
+            SpeedOfLightAdjustment::Increase => *c * SPEED_OF_LIGHT_STEP,
+            SpeedOfLightAdjustment::Decrease => *c / SPEED_OF_LIGHT_STEP,
+        }
+        .clamp(*SPEED_OF_LIGHT_RANGE.start(), *SPEED_OF_LIGHT_RANGE.end());
+    }
+}
+
+/// The 1PN corrections of the Einstein-Infeld-Hoffmann equations to the
+/// Newtonian `accelerations` of `bodies`, at the speed of light `c`.
+/// The corrections depend on the accelerations of the other bodies,
+/// for which the Newtonian ones are used, as is accurate to this order.
+pub fn get_corrections(bodies: &[Body], accelerations: &[Vector], c: f64) -> Vec<Vector> {
+    let c_2 = c.powi(2);
+
+    // The Newtonian potential at every body, up to the sign
+    let potentials = bodies
+        .iter()
+        .enumerate()
+        .map(|(a, lhs)| {
+            bodies
+                .iter()
+                .enumerate()
+                .filter(|(b, rhs)| *b != a && rhs.pos != lhs.pos)
+                .map(|(_, rhs)| G.get() * rhs.mass / (rhs.pos - lhs.pos).length())
+                .sum::<f64>()
+        })
+        .collect::<Vec<_>>();
+
+    bodies
+        .iter()
+        .enumerate()
+        .map(|(a, lhs)| {
+            let mut correction = Vector::ZERO;
+
+            for (b, rhs) in bodies.iter().enumerate() {
+                if b == a || rhs.pos == lhs.pos {
+                    continue;
+                }
+
+                let r = rhs.pos - lhs.pos;
+                let distance = r.length();
+                // Pointing from `rhs` to `lhs`
+                let n = -r / distance;
+                let (v_a, v_b) = (lhs.speed, rhs.speed);
+
+                let factor =
+                    (-4.0 * potentials[a] - potentials[b] + v_a.dot(v_a) + 2.0 * v_b.dot(v_b)
+                        - 4.0 * v_a.dot(v_b)
+                        - 1.5 * n.dot(v_b).powi(2)
+                        + 0.5 * r.dot(accelerations[b]))
+                        / c_2;
+
+                correction += G.get() * rhs.mass / distance.powi(2) * factor * -n
+                    + G.get() * rhs.mass / (c_2 * distance.powi(2))
+                        * n.dot(4.0 * v_a - 3.0 * v_b)
+                        * (v_a - v_b)
+                    + 3.5 * G.get() * rhs.mass / (c_2 * distance) * accelerations[b];
+            }
+
+            correction
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        body::{BodyID, DT},
+        direct::Direct,
+        force_law::ForceLaw,
+        vector::VectorExt,
+    };
+    use std::{collections::HashMap, f64::consts::PI};
+
+    const STAR_MASS: f64 = 1000.0;
+    const PLANET_MASS: f64 = 1.0;
+    const SEMI_MAJOR_AXIS: f64 = 400.0;
+    const ECCENTRICITY: f64 = 0.5;
+    const ORBITS_N: usize = 3;
+
+    /// A star and a planet starting at the apocentre, around their centre of mass.
+    fn get_bodies() -> HashMap<BodyID, Body> {
+        let total_mass = STAR_MASS + PLANET_MASS;
+        let mu = G.get() * total_mass;
+        let distance = SEMI_MAJOR_AXIS * (1.0 + ECCENTRICITY);
+        let speed = (mu / SEMI_MAJOR_AXIS * (1.0 - ECCENTRICITY) / (1.0 + ECCENTRICITY)).sqrt();
+
+        let body = |mass: f64, share: f64| Body {
+            pos: Vector::from_xy(share * distance, 0.0),
+            speed: Vector::from_xy(0.0, share * speed),
+            mass,
+            radius: 1.0,
+            density: 1.0,
+            charge: 1.0,
+            species: 0,
+        };
+
+        HashMap::from([
+            (0, body(STAR_MASS, -PLANET_MASS / total_mass)),
+            (1, body(PLANET_MASS, STAR_MASS / total_mass)),
+        ])
+    }
+
+    /// The angle of the Laplace-Runge-Lenz vector of the relative orbit, which
+    /// points at the pericentre.
+    fn get_pericentre_angle(bodies: &HashMap<BodyID, Body>) -> f64 {
+        let r = bodies[&1].pos - bodies[&0].pos;
+        let v = bodies[&1].speed - bodies[&0].speed;
+        let mu = G.get() * (STAR_MASS + PLANET_MASS);
+        let lrl = r * v.dot(v) - v * r.dot(v) - mu * r / r.length();
+
+        let components = lrl.components();
+        components[1].atan2(components[0])
+    }
+
+    /// Integrates `ORBITS_N` orbits with leapfrog and the direct solver at the
+    /// speed of light `c`, and returns how far the pericentre advanced per orbit,
+    /// measured at every apocentre so that the orbits are compared at the same phase.
+    fn get_precession(c: Option<f64>) -> f64 {
+        let mut bodies = get_bodies();
+        let initial_angle = get_pericentre_angle(&bodies);
+        let get_distance =
+            |bodies: &HashMap<BodyID, Body>| (bodies[&1].pos - bodies[&0].pos).length();
+
+        let mut distances = [get_distance(&bodies); 2];
+        let mut apocentres = Vec::with_capacity(ORBITS_N);
+        while apocentres.len() < ORBITS_N {
+            let angle = get_pericentre_angle(&bodies);
+
+            Body::drift(DT.get() / 2.0, &mut bodies);
+            Direct::handle_with_speed_of_light(&mut bodies, ForceLaw::Newtonian, c);
+            Body::drift(DT.get() / 2.0, &mut bodies);
+
+            let distance = get_distance(&bodies);
+            if distances[1] > distances[0] && distances[1] >= distance {
+                apocentres.push(angle);
+            }
+            distances = [distances[1], distance];
+        }
+
+        let mut advance = apocentres[ORBITS_N - 1] - initial_angle;
+        // The advance is small, so it is within half a turn either way
+        advance = (advance + PI).rem_euclid(2.0 * PI) - PI;
+
+        advance / ORBITS_N as f64
+    }
+
+    /// The perihelion of Mercury: an orbit precesses by 6 pi G M / (c^2 a (1 - e^2))
+    /// per revolution under 1PN, and not at all under Newtonian gravity.
+    #[test]
+    fn perihelion_precession() {
+        let c: f64 = 10.0;
+        let expected = 6.0 * PI * G.get() * (STAR_MASS + PLANET_MASS)
+            / (c.powi(2) * SEMI_MAJOR_AXIS * (1.0 - ECCENTRICITY.powi(2)));
+
+        let newtonian = get_precession(None);
+        let post_newtonian = get_precession(Some(c));
+
+        assert!(
+            newtonian.abs() < 0.01 * expected,
+            "{} without 1PN",
+            newtonian
+        );
+        assert!(
+            (post_newtonian - expected).abs() < 0.02 * expected,
+            "{} instead of {}",
+            post_newtonian,
+            expected
+        );
+    }
+}
@@ -0,0 +1,749 @@
+use crate::{
+    barnes_hut::{self, BarnesHut, MAX_THETA, Mac, THETA, Traversal},
+    body::{Body, BodyID, COLLISIONS, Collisions, DT, G, INITIAL_DENSITY, INITIAL_MASS},
+    camera::ZOOM_RANGE,
+    drag::{DUST, Drag, MEDIUM, Medium, SPECIES, set_species},
+    external_field::{EXTERNAL_FIELDS, ExternalField},
+    force_law::{FORCE_LAW, ForceLaw},
+    frame::RotatingFrame,
+    grid::{self, TAU, TAU_RANGE},
+    import::Import,
+    initial_conditions::{Dispersion, InitialConditions, MassSpectrum},
+    multigrid,
+    output::TrajectoryOutput,
+    post_newtonian::SPEED_OF_LIGHT,
+    solver::{INTEGRATOR, Integrator, Solver},
+    units::{Quantity, SCALES, Scales, Units},
+    vector::{Vector, VectorExt},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    num::NonZero,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_BODIES_N: NonZero<usize> = NonZero::new(
+    //500 // Recommended for watching the deterministic chaos
+    1500,
+)
+.unwrap();
+/// The planets `InitialConditions::SolarSystem` knows the masses of.
+const MAX_PLANETS_N: usize = 8;
+
+/// Everything a run starts from, read from a TOML file. Whatever is left out
+/// keeps its default, which is what the viewer starts with.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    /// What the numbers of the scenario, and of its outputs, are in.
+    pub units: Units,
+    pub constants: Constants,
+    pub initial_conditions: Generation,
+    pub forces: Forces,
+    /// The frame the simulations start in, the inertial one if left out.
+    pub frame: Option<FrameConfig>,
+    /// The simulations running side by side, the first one leading the others.
+    pub solvers: Vec<SolverConfig>,
+    pub integrator: Integrator,
+    pub collisions: Collisions,
+    pub outputs: Outputs,
+    /// Where the file is, which its paths are relative to, the working directory
+    /// if there is none.
+    #[serde(skip)]
+    pub directory: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Constants {
+    /// G in the simulation's units, whatever the scenario's, which sets how long
+    /// a unit of time is.
+    pub g: f64,
+    pub dt: f64,
+    /// The starting theta of Barnes-Hut, which is then tuned against the grid.
+    pub theta: f64,
+    pub tau: f64,
+    /// How far the wheel zooms, while following everything zooms as far as it takes.
+    pub zoom_range: Range<f32>,
+    pub draw: Draw,
+}
+
+/// Which solvers draw their structure over the bodies.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Draw {
+    pub barnes_hut: bool,
+    pub multigrid: bool,
+    pub grid: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Generation {
+    /// Centred on the origin, the first preset of the viewer if left out.
+    pub generator: Option<InitialConditions>,
+    /// Bodies to start from instead of generating them, where the file has them
+    /// unless recentred. The number of bodies and the masses are then the file's.
+    pub import: Option<Import>,
+    pub bodies_n: NonZero<usize>,
+    /// A random one if left out.
+    pub seed: Option<u64>,
+    pub mass_spectrum: MassSpectrum,
+    pub density: f64,
+    /// The fraction of the generated bodies that are dust, dragged by the medium.
+    pub dust_fraction: f64,
+}
+
+/// What the bodies feel besides one another, and the law they feel one another
+/// through. The viewer's keys cycle from these through its presets.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Forces {
+    pub law: ForceLaw,
+    /// That of the 1PN corrections of the direct solver, which are off if left out.
+    pub speed_of_light: Option<f64>,
+    pub external_fields: Vec<ExternalField>,
+    /// The gas the dust is dragged against, none if left out.
+    pub medium: Option<Medium>,
+    pub dust_drag: Drag,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum FrameConfig {
+    /// The frame in which the bar among the external fields stands still if there
+    /// is one, or else the one turning with the bodies, like the F key's.
+    Corotating,
+    /// Turning about the origin at `angular_speed`.
+    Rotating { angular_speed: f64 },
+}
+
+/// The braces of the variants without options make serde reject unknown keys in them.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SolverConfig {
+    Direct {},
+    BarnesHut {
+        #[serde(default)]
+        traversal: Traversal,
+        #[serde(default)]
+        mac: Mac,
+        #[serde(default)]
+        incremental: bool,
+    },
+    #[serde(rename = "multigrid")]
+    MultiGrid {},
+    Grid {},
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Outputs {
+    /// Whether to measure the energy after every step, as the E key toggles.
+    pub energy_diagnostics: bool,
+    /// Where to stream the bodies of every simulation to, for analysis elsewhere.
+    pub trajectories: Vec<TrajectoryOutput>,
+}
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "cannot read the scenario: {}", error),
+            Self::Parse(error) => write!(f, "cannot parse the scenario: {}", error),
+            Self::Invalid(message) => write!(f, "invalid scenario: {}", message),
+        }
+    }
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            units: Units::default(),
+            constants: Constants::default(),
+            initial_conditions: Generation::default(),
+            forces: Forces::default(),
+            frame: None,
+            solvers: vec![
+                SolverConfig::Direct {},
+                SolverConfig::BarnesHut {
+                    traversal: Traversal::Bodies,
+                    mac: Mac::default(),
+                    incremental: false,
+                },
+                SolverConfig::BarnesHut {
+                    traversal: Traversal::DualTree,
+                    mac: Mac::default(),
+                    incremental: false,
+                },
+                SolverConfig::MultiGrid {},
+                SolverConfig::Grid {},
+            ],
+            integrator: Integrator::default(),
+            collisions: Collisions::default(),
+            outputs: Outputs::default(),
+            directory: PathBuf::new(),
+        }
+    }
+}
+
+impl Default for Constants {
+    fn default() -> Self {
+        Self {
+            g: G.get(),
+            dt: DT.get(),
+            theta: *THETA.read().unwrap(),
+            tau: *TAU.read().unwrap(),
+            zoom_range: ZOOM_RANGE,
+            draw: Draw::default(),
+        }
+    }
+}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Self {
+            generator: None,
+            import: None,
+            bodies_n: DEFAULT_BODIES_N,
+            seed: None,
+            mass_spectrum: MassSpectrum::Equal(INITIAL_MASS),
+            density: INITIAL_DENSITY,
+            dust_fraction: 0.0,
+        }
+    }
+}
+
+impl Generation {
+    /// The same with the numbers of the scenario's units in the simulation's.
+    pub fn to_simulation(&self, scales: &Scales) -> Self {
+        Self {
+            generator: self
+                .generator
+                .map(|generator| generator.to_simulation(scales)),
+            import: self.import.clone().map(|mut import| {
+                import.length = scales.in_simulation(Quantity::Length, import.length);
+                import.speed = scales.in_simulation(Quantity::Speed, import.speed);
+                import.mass = scales.in_simulation(Quantity::Mass, import.mass);
+                import
+            }),
+            bodies_n: self.bodies_n,
+            seed: self.seed,
+            mass_spectrum: self.mass_spectrum.to_simulation(scales),
+            density: scales.in_simulation(Quantity::Density, self.density),
+            dust_fraction: self.dust_fraction,
+        }
+    }
+}
+
+impl Forces {
+    /// Those the simulations step with now, in the simulation's units.
+    pub fn read() -> Self {
+        Self {
+            law: *FORCE_LAW.read().unwrap(),
+            speed_of_light: *SPEED_OF_LIGHT.read().unwrap(),
+            external_fields: EXTERNAL_FIELDS.read().unwrap().clone(),
+            medium: *MEDIUM.read().unwrap(),
+            dust_drag: SPECIES
+                .read()
+                .unwrap()
+                .get(DUST)
+                .and_then(|species| species.drag)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The same with the numbers of the scenario's units in the simulation's.
+    pub fn to_simulation(&self, scales: &Scales) -> Self {
+        Self {
+            law: self.law.to_simulation(scales),
+            speed_of_light: self
+                .speed_of_light
+                .map(|c| scales.in_simulation(Quantity::Speed, c)),
+            external_fields: self
+                .external_fields
+                .iter()
+                .map(|external_field| external_field.to_simulation(scales))
+                .collect(),
+            medium: self.medium.map(|medium| medium.to_simulation(scales)),
+            dust_drag: self.dust_drag.to_simulation(scales),
+        }
+    }
+
+    /// Sets the models the simulations step with, which must be in the simulation's units.
+    pub fn apply(&self) {
+        *FORCE_LAW.write().unwrap() = self.law;
+        *SPEED_OF_LIGHT.write().unwrap() = self.speed_of_light;
+        *EXTERNAL_FIELDS.write().unwrap() = self.external_fields.clone();
+        *MEDIUM.write().unwrap() = self.medium;
+        set_species(self.dust_drag);
+    }
+}
+
+impl FrameConfig {
+    /// The same with the numbers of the scenario's units in the simulation's.
+    pub fn to_simulation(self, scales: &Scales) -> Self {
+        match self {
+            Self::Corotating => self,
+            Self::Rotating { angular_speed } => Self::Rotating {
+                angular_speed: scales.in_simulation(Quantity::Frequency, angular_speed),
+            },
+        }
+    }
+
+    /// The frame of `bodies` at `time`, under the external fields being applied.
+    pub fn build(&self, bodies: &HashMap<BodyID, Body>, time: f64) -> RotatingFrame {
+        match *self {
+            Self::Corotating => {
+                RotatingFrame::new_standing(&EXTERNAL_FIELDS.read().unwrap(), bodies, time)
+            }
+            Self::Rotating { angular_speed } => RotatingFrame {
+                center: Vector::ZERO,
+                angular_speed,
+                angle: angular_speed * time,
+            },
+        }
+    }
+}
+
+impl SolverConfig {
+    pub fn build(&self) -> Solver {
+        match *self {
+            Self::Direct {} => Solver::Direct,
+            Self::BarnesHut {
+                traversal,
+                mac,
+                incremental,
+            } => Solver::BarnesHut(BarnesHut {
+                traversal,
+                mac,
+                incremental,
+                ..Default::default()
+            }),
+            Self::MultiGrid {} => Solver::MultiGrid,
+            Self::Grid {} => Solver::Grid,
+        }
+    }
+}
+
+/// Fails unless `value` is finite and above zero.
+fn check_positive(name: &str, value: f64) -> Result<(), ScenarioError> {
+    if value > 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(ScenarioError::Invalid(format!(
+            "`{}` must be positive, not {}",
+            name, value
+        )))
+    }
+}
+
+/// Fails unless `value` is finite and not below zero.
+fn check_non_negative(name: &str, value: f64) -> Result<(), ScenarioError> {
+    if value >= 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(ScenarioError::Invalid(format!(
+            "`{}` must not be negative, not {}",
+            name, value
+        )))
+    }
+}
+
+fn check_finite(name: &str, value: f64) -> Result<(), ScenarioError> {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(ScenarioError::Invalid(format!(
+            "`{}` must be finite, not {}",
+            name, value
+        )))
+    }
+}
+
+fn check_vector(name: &str, value: Vector) -> Result<(), ScenarioError> {
+    if value.components().into_iter().all(f64::is_finite) {
+        Ok(())
+    } else {
+        Err(ScenarioError::Invalid(format!(
+            "`{}` must be finite, not {:?}",
+            name,
+            value.components()
+        )))
+    }
+}
+
+fn check_forces(forces: &Forces) -> Result<(), ScenarioError> {
+    match forces.law {
+        // The logarithmic law is the gravity of a 2D world
+        ForceLaw::Logarithmic if cfg!(feature = "3d") => {
+            return Err(ScenarioError::Invalid(
+                "`forces.law` can't be `logarithmic` in 3D".to_owned(),
+            ));
+        }
+        ForceLaw::Yukawa { length } => check_positive("forces.law.yukawa.length", length)?,
+        _ => {}
+    }
+    if let Some(c) = forces.speed_of_light {
+        check_positive("forces.speed_of_light", c)?;
+    }
+
+    for (index, external_field) in forces.external_fields.iter().enumerate() {
+        let name = |variant: &str, field: &str| {
+            format!("forces.external_fields[{}].{}.{}", index, variant, field)
+        };
+
+        match *external_field {
+            ExternalField::PointMass { pos, mass } => {
+                check_vector(&name("point_mass", "pos"), pos)?;
+                check_positive(&name("point_mass", "mass"), mass)?;
+            }
+            ExternalField::Plummer { pos, mass, radius } => {
+                check_vector(&name("plummer", "pos"), pos)?;
+                check_positive(&name("plummer", "mass"), mass)?;
+                check_positive(&name("plummer", "radius"), radius)?;
+            }
+            ExternalField::LogarithmicHalo {
+                pos,
+                circular_speed,
+                core_radius,
+            } => {
+                check_vector(&name("logarithmic_halo", "pos"), pos)?;
+                check_positive(&name("logarithmic_halo", "circular_speed"), circular_speed)?;
+                check_positive(&name("logarithmic_halo", "core_radius"), core_radius)?;
+            }
+            ExternalField::Uniform { acceleration } => {
+                check_vector(&name("uniform", "acceleration"), acceleration)?;
+            }
+            ExternalField::RotatingBar {
+                pos,
+                mass,
+                half_length,
+                radius,
+                pattern_speed,
+            } => {
+                check_vector(&name("rotating_bar", "pos"), pos)?;
+                check_positive(&name("rotating_bar", "mass"), mass)?;
+                check_positive(&name("rotating_bar", "half_length"), half_length)?;
+                check_positive(&name("rotating_bar", "radius"), radius)?;
+                check_finite(&name("rotating_bar", "pattern_speed"), pattern_speed)?;
+            }
+            ExternalField::Tidal {
+                pos,
+                direction,
+                strength,
+            } => {
+                check_vector(&name("tidal", "pos"), pos)?;
+                check_vector(&name("tidal", "direction"), direction)?;
+                if direction == Vector::ZERO {
+                    return Err(ScenarioError::Invalid(format!(
+                        "`{}` must not be zero",
+                        name("tidal", "direction")
+                    )));
+                }
+                check_positive(&name("tidal", "strength"), strength)?;
+            }
+        }
+    }
+
+    if let Some(medium) = &forces.medium {
+        check_vector("forces.medium.center", medium.center)?;
+        check_finite("forces.medium.circular_speed", medium.circular_speed)?;
+        check_positive("forces.medium.density", medium.density)?;
+        check_positive("forces.medium.thermal_speed", medium.thermal_speed)?;
+        check_positive("forces.medium.mean_free_path", medium.mean_free_path)?;
+    }
+
+    match forces.dust_drag {
+        Drag::Linear { rate } => check_non_negative("forces.dust_drag.linear.rate", rate),
+        Drag::Quadratic { coefficient } => {
+            check_non_negative("forces.dust_drag.quadratic.coefficient", coefficient)
+        }
+        Drag::Epstein | Drag::Stokes => Ok(()),
+    }
+}
+
+fn check_generator(generator: &InitialConditions) -> Result<(), ScenarioError> {
+    let prefix = "initial_conditions.generator";
+    let name = |variant: &str, field: &str| format!("{}.{}.{}", prefix, variant, field);
+
+    match *generator {
+        InitialConditions::UniformDisk {
+            radius,
+            aspect,
+            speed,
+            ..
+        } => {
+            check_positive(&name("uniform_disk", "radius"), radius)?;
+            check_positive(&name("uniform_disk", "aspect"), aspect)?;
+            check_non_negative(&name("uniform_disk", "speed"), speed)
+        }
+        InitialConditions::Plummer { radius, .. } => {
+            check_positive(&name("plummer", "radius"), radius)
+        }
+        InitialConditions::Hernquist { radius, .. } => {
+            check_positive(&name("hernquist", "radius"), radius)
+        }
+        InitialConditions::King {
+            radius,
+            concentration,
+            ..
+        } => {
+            check_positive(&name("king", "radius"), radius)?;
+            check_positive(&name("king", "concentration"), concentration)
+        }
+        InitialConditions::KeplerianDisk {
+            central_mass,
+            inner_radius,
+            outer_radius,
+            ..
+        } => {
+            check_positive(&name("keplerian_disk", "central_mass"), central_mass)?;
+            check_positive(&name("keplerian_disk", "inner_radius"), inner_radius)?;
+            check_positive(&name("keplerian_disk", "outer_radius"), outer_radius)?;
+            if inner_radius < outer_radius {
+                Ok(())
+            } else {
+                Err(ScenarioError::Invalid(format!(
+                    "`{}` must be below `{}`, but {} >= {}",
+                    name("keplerian_disk", "inner_radius"),
+                    name("keplerian_disk", "outer_radius"),
+                    inner_radius,
+                    outer_radius
+                )))
+            }
+        }
+        InitialConditions::RotatingDisk {
+            scale_length,
+            dispersion,
+            ..
+        } => {
+            check_positive(&name("rotating_disk", "scale_length"), scale_length)?;
+            match dispersion {
+                Dispersion::ToomreQ(q) => {
+                    check_positive(&name("rotating_disk", "dispersion.toomre_q"), q)
+                }
+                Dispersion::Constant(dispersion) => {
+                    check_non_negative(&name("rotating_disk", "dispersion.constant"), dispersion)
+                }
+            }
+        }
+        InitialConditions::Lattice { spacing, .. } => {
+            check_positive(&name("lattice", "spacing"), spacing)
+        }
+        InitialConditions::TwoGalaxies {
+            central_mass,
+            radius,
+            separation,
+            impact_parameter,
+            approach_speed,
+            ..
+        } => {
+            check_positive(&name("two_galaxies", "central_mass"), central_mass)?;
+            check_positive(&name("two_galaxies", "radius"), radius)?;
+            check_non_negative(&name("two_galaxies", "separation"), separation)?;
+            check_finite(&name("two_galaxies", "impact_parameter"), impact_parameter)?;
+            check_finite(&name("two_galaxies", "approach_speed"), approach_speed)
+        }
+        InitialConditions::SolarSystem {
+            star_mass,
+            earth_mass,
+            planets_n,
+            innermost_orbit,
+            orbit_ratio,
+            ..
+        } => {
+            check_positive(&name("solar_system", "star_mass"), star_mass)?;
+            check_positive(&name("solar_system", "earth_mass"), earth_mass)?;
+            check_positive(&name("solar_system", "innermost_orbit"), innermost_orbit)?;
+            if planets_n > MAX_PLANETS_N {
+                return Err(ScenarioError::Invalid(format!(
+                    "`{}` must be at most {}, not {}",
+                    name("solar_system", "planets_n"),
+                    MAX_PLANETS_N,
+                    planets_n
+                )));
+            }
+            if orbit_ratio > 1.0 && orbit_ratio.is_finite() {
+                Ok(())
+            } else {
+                Err(ScenarioError::Invalid(format!(
+                    "`{}` must be above 1, not {}",
+                    name("solar_system", "orbit_ratio"),
+                    orbit_ratio
+                )))
+            }
+        }
+    }
+}
+
+fn check_mass_spectrum(mass_spectrum: &MassSpectrum) -> Result<(), ScenarioError> {
+    let prefix = "initial_conditions.mass_spectrum";
+    let check_range = |variant: &str, min: f64, max: f64| {
+        check_positive(&format!("{}.{}.min", prefix, variant), min)?;
+        check_positive(&format!("{}.{}.max", prefix, variant), max)?;
+        if min <= max {
+            Ok(())
+        } else {
+            Err(ScenarioError::Invalid(format!(
+                "`{}.{}.min` must not be above `max`, but {} > {}",
+                prefix, variant, min, max
+            )))
+        }
+    };
+
+    match *mass_spectrum {
+        MassSpectrum::Equal(mass) => check_positive(&format!("{}.equal", prefix), mass),
+        MassSpectrum::PowerLaw { exponent, min, max } => {
+            check_finite(&format!("{}.power_law.exponent", prefix), exponent)?;
+            check_range("power_law", min, max)
+        }
+        MassSpectrum::Salpeter { min, max } => check_range("salpeter", min, max),
+        MassSpectrum::LogNormal { median, sigma } => {
+            check_positive(&format!("{}.log_normal.median", prefix), median)?;
+            check_non_negative(&format!("{}.log_normal.sigma", prefix), sigma)
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, ScenarioError> {
+        let text = fs::read_to_string(path).map_err(ScenarioError::Io)?;
+        let mut scenario = toml::from_str::<Self>(&text).map_err(ScenarioError::Parse)?;
+        scenario.validate()?;
+        scenario.directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        Ok(scenario)
+    }
+
+    /// Checks what the format alone doesn't, naming the offending key.
+    pub fn validate(&self) -> Result<(), ScenarioError> {
+        check_positive("units.length", self.units.length)?;
+        check_positive("units.mass", self.units.mass)?;
+
+        let constants = &self.constants;
+        check_positive("constants.g", constants.g)?;
+        check_positive("constants.dt", constants.dt)?;
+        if !(0.0..=MAX_THETA).contains(&constants.theta) {
+            return Err(ScenarioError::Invalid(format!(
+                "`constants.theta` must be between 0 and {}, not {}",
+                MAX_THETA, constants.theta
+            )));
+        }
+        if !TAU_RANGE.contains(&constants.tau) {
+            return Err(ScenarioError::Invalid(format!(
+                "`constants.tau` must be between {} and {}, not {}",
+                TAU_RANGE.start(),
+                TAU_RANGE.end(),
+                constants.tau
+            )));
+        }
+        let zoom_range = &constants.zoom_range;
+        if !(zoom_range.start > 0.0 && zoom_range.end.is_finite() && zoom_range.contains(&1.0)) {
+            return Err(ScenarioError::Invalid(format!(
+                "`constants.zoom_range` must be positive and contain 1, not {:?}",
+                zoom_range
+            )));
+        }
+
+        let generation = &self.initial_conditions;
+        if let Some(generator) = &generation.generator {
+            check_generator(generator)?;
+        }
+        check_mass_spectrum(&generation.mass_spectrum)?;
+        check_positive("initial_conditions.density", generation.density)?;
+        if !(0.0..=1.0).contains(&generation.dust_fraction) {
+            return Err(ScenarioError::Invalid(format!(
+                "`initial_conditions.dust_fraction` must be between 0 and 1, not {}",
+                generation.dust_fraction
+            )));
+        }
+        if let Some(import) = &generation.import {
+            if generation.generator.is_some() {
+                return Err(ScenarioError::Invalid(
+                    "`initial_conditions.generator` and `initial_conditions.import` \
+                     can't both be given"
+                        .to_owned(),
+                ));
+            }
+            check_positive("initial_conditions.import.length", import.length)?;
+            check_positive("initial_conditions.import.speed", import.speed)?;
+            check_positive("initial_conditions.import.mass", import.mass)?;
+        }
+
+        check_forces(&self.forces)?;
+        if let Some(FrameConfig::Rotating { angular_speed }) = self.frame {
+            check_finite("frame.rotating.angular_speed", angular_speed)?;
+        }
+
+        if self.solvers.is_empty() {
+            return Err(ScenarioError::Invalid(
+                "`solvers` must list at least one solver".to_owned(),
+            ));
+        }
+        for (index, solver) in self.solvers.iter().enumerate() {
+            if let SolverConfig::BarnesHut {
+                traversal: Traversal::DualTree,
+                mac: Mac::RelativeAcceleration,
+                ..
+            } = solver
+            {
+                return Err(ScenarioError::Invalid(format!(
+                    "`solvers[{}].mac` can't be `relative_acceleration` with the `dual_tree` \
+                     traversal, which has no accelerations per body to compare against",
+                    index
+                )));
+            }
+        }
+
+        for (index, trajectory) in self.outputs.trajectories.iter().enumerate() {
+            if trajectory.path.as_os_str().is_empty() {
+                return Err(ScenarioError::Invalid(format!(
+                    "`outputs.trajectories[{}].path` must not be empty",
+                    index
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The sizes of the simulation's units in the scenario's.
+    pub fn get_scales(&self) -> Scales {
+        self.units.get_scales(self.constants.g)
+    }
+
+    /// Sets the global constants and models the solvers read, and the scales
+    /// the outputs are converted with.
+    pub fn apply(&self) {
+        let constants = &self.constants;
+        let scales = self.get_scales();
+        G.set(constants.g);
+        DT.set(scales.in_simulation(Quantity::Time, constants.dt));
+        *SCALES.write().unwrap() = scales;
+        *THETA.write().unwrap() = constants.theta;
+        *TAU.write().unwrap() = constants.tau;
+        *barnes_hut::DRAW.write().unwrap() = constants.draw.barnes_hut;
+        *multigrid::DRAW.write().unwrap() = constants.draw.multigrid;
+        *grid::DRAW.write().unwrap() = constants.draw.grid;
+
+        *INTEGRATOR.write().unwrap() = self.integrator;
+        *COLLISIONS.write().unwrap() = self.collisions;
+        self.forces.to_simulation(&scales).apply();
+    }
+
+    /// The frame the simulations of `bodies` start in, in the simulation's units.
+    pub fn get_frame(&self, bodies: &HashMap<BodyID, Body>) -> Option<RotatingFrame> {
+        self.frame
+            .map(|frame| frame.to_simulation(&self.get_scales()).build(bodies, 0.0))
+    }
+}
@@ -0,0 +1,630 @@
+use crate::{
+    barnes_hut::{BarnesHut, Mac, THETA, Traversal},
+    body::{Body, COLLISIONS, Collisions, DT, G, reserve_body_id},
+    drag::DUST,
+    force_law::ForceLaw,
+    frame::RotatingFrame,
+    grid::TAU,
+    scenario::Forces,
+    solver::{INTEGRATOR, Integrator, Simulation, Solver},
+    units::{SCALES, Units},
+    vector::{DIMENSIONS, Vector, VectorExt},
+};
+use rand_chacha::{ChaCha12Rng, rand_core::SeedableRng};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"GRAVSNAP";
+/// Bumped whenever the layout changes, as older snapshots can't be read then.
+const VERSION: u32 = 3;
+
+/// The state to restart the simulations from, as read from a file.
+///
+/// A snapshot is little-endian: the magic, the version, the number of dimensions,
+/// the constants, the forces and the units as the scenario writes them but in JSON,
+/// the seed of the initial conditions, the random generator, then every simulation
+/// with its solver, time, steps, energy baseline, frame, bodies and the
+/// accelerations Barnes-Hut keeps from the previous step.
+pub struct Snapshot {
+    pub g: f64,
+    pub dt: f64,
+    pub theta: f64,
+    pub tau: f64,
+    pub integrator: Integrator,
+    pub collisions: Collisions,
+    /// In the simulation's units.
+    pub forces: Forces,
+    pub units: Units,
+    pub seed: u64,
+    /// The generator the viewer draws new seeds from.
+    pub rng: ChaCha12Rng,
+    pub simulations: Vec<Simulation>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    NotASnapshot,
+    Version(u32),
+    Dimensions(u8),
+    Invalid(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{}", error),
+            Self::NotASnapshot => write!(f, "not a snapshot"),
+            Self::Version(version) => write!(
+                f,
+                "a snapshot of version {}, while this build reads version {}",
+                version, VERSION
+            ),
+            Self::Dimensions(dimensions) => write!(
+                f,
+                "a snapshot in {}D, while this build simulates in {}D",
+                dimensions, DIMENSIONS
+            ),
+            Self::Invalid(message) => write!(f, "corrupt snapshot: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+struct Writer<W: Write>(W);
+
+impl<W: Write> Writer<W> {
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.0.write_all(&[value])
+    }
+
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn u128(&mut self, value: u128) -> io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn f64(&mut self, value: f64) -> io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn vector(&mut self, value: Vector) -> io::Result<()> {
+        value
+            .components()
+            .into_iter()
+            .try_for_each(|component| self.f64(component))
+    }
+
+    fn option_f64(&mut self, value: Option<f64>) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                self.u8(1)?;
+                self.f64(value)
+            }
+            None => self.u8(0),
+        }
+    }
+
+    /// Its length, then the JSON.
+    fn json(&mut self, value: &impl Serialize) -> io::Result<()> {
+        let json = serde_json::to_vec(value)?;
+        self.u32(json.len() as u32)?;
+        self.0.write_all(&json)
+    }
+}
+
+struct Reader<R: Read>(R);
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.0.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn u128(&mut self) -> io::Result<u128> {
+        Ok(u128::from_le_bytes(self.bytes()?))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes()?))
+    }
+
+    fn vector(&mut self) -> io::Result<Vector> {
+        let mut components = [0.0; DIMENSIONS];
+        for component in &mut components {
+            *component = self.f64()?;
+        }
+        Ok(Vector::from_components(components))
+    }
+
+    fn option_f64(&mut self) -> Result<Option<f64>, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.f64()?)),
+            tag => Err(invalid("option", tag)),
+        }
+    }
+
+    /// Reads no further than its length, whatever the JSON says.
+    fn json<T: DeserializeOwned>(&mut self) -> Result<T, SnapshotError> {
+        let length = self.u32()?;
+        serde_json::from_reader((&mut self.0).take(length as u64))
+            .map_err(|error| SnapshotError::Invalid(error.to_string()))
+    }
+}
+
+fn invalid(what: &str, tag: u8) -> SnapshotError {
+    SnapshotError::Invalid(format!("unknown {} {}", what, tag))
+}
+
+/// The solver with its options, as a kind, a traversal, a MAC and whether it is incremental.
+fn get_solver_tags(solver: &Solver) -> [u8; 4] {
+    match solver {
+        Solver::Direct => [0, 0, 0, 0],
+        Solver::BarnesHut(barnes_hut) => [
+            1,
+            match barnes_hut.traversal {
+                Traversal::Bodies => 0,
+                Traversal::DualTree => 1,
+            },
+            match barnes_hut.mac {
+                Mac::Geometric => 0,
+                Mac::MinDistance => 1,
+                Mac::Bmax => 2,
+                Mac::RelativeAcceleration => 3,
+            },
+            barnes_hut.incremental as u8,
+        ],
+        Solver::MultiGrid => [2, 0, 0, 0],
+        Solver::Grid => [3, 0, 0, 0],
+    }
+}
+
+fn get_solver(tags: [u8; 4]) -> Result<Solver, SnapshotError> {
+    let [kind, traversal, mac, incremental] = tags;
+
+    Ok(match kind {
+        0 => Solver::Direct,
+        1 => Solver::BarnesHut(BarnesHut {
+            traversal: match traversal {
+                0 => Traversal::Bodies,
+                1 => Traversal::DualTree,
+                _ => return Err(invalid("traversal", traversal)),
+            },
+            mac: match mac {
+                0 => Mac::Geometric,
+                1 => Mac::MinDistance,
+                2 => Mac::Bmax,
+                3 => Mac::RelativeAcceleration,
+                _ => return Err(invalid("MAC", mac)),
+            },
+            incremental: incremental != 0,
+            ..Default::default()
+        }),
+        2 => Solver::MultiGrid,
+        3 => Solver::Grid,
+        _ => return Err(invalid("solver", kind)),
+    })
+}
+
+/// Writes the simulations and the global state to `path`, through a temporary file
+/// so that an interrupted write leaves the previous snapshot intact.
+pub fn save(
+    path: &Path,
+    simulations: &[Simulation],
+    units: &Units,
+    seed: u64,
+    rng: &ChaCha12Rng,
+) -> Result<(), SnapshotError> {
+    let temporary_path = path.with_extension("tmp");
+    let mut writer = Writer(BufWriter::new(File::create(&temporary_path)?));
+
+    writer.0.write_all(MAGIC)?;
+    writer.u32(VERSION)?;
+    writer.u8(DIMENSIONS as u8)?;
+
+    writer.f64(G.get())?;
+    writer.f64(DT.get())?;
+    writer.f64(*THETA.read().unwrap())?;
+    writer.f64(*TAU.read().unwrap())?;
+    writer.u8(match *INTEGRATOR.read().unwrap() {
+        Integrator::SymplecticEuler => 0,
+        Integrator::Leapfrog => 1,
+    })?;
+    writer.u8(match *COLLISIONS.read().unwrap() {
+        Collisions::Merge => 0,
+        Collisions::None => 1,
+    })?;
+
+    writer.json(&Forces::read())?;
+    writer.json(units)?;
+
+    writer.u64(seed)?;
+    writer.0.write_all(&rng.get_seed())?;
+    writer.u64(rng.get_stream())?;
+    writer.u128(rng.get_word_pos())?;
+
+    writer.u32(simulations.len() as u32)?;
+    for simulation in simulations {
+        writer.0.write_all(&get_solver_tags(&simulation.solver))?;
+        writer.f64(simulation.time)?;
+        writer.u64(simulation.steps)?;
+        writer.option_f64(simulation.initial_energy)?;
+
+        match &simulation.frame {
+            Some(frame) => {
+                writer.u8(1)?;
+                writer.vector(frame.center)?;
+                writer.f64(frame.angular_speed)?;
+                writer.f64(frame.angle)?;
+            }
+            None => writer.u8(0)?,
+        }
+
+        // In the order they were created in, so that the same state gives the same file
+        let mut body_ids = simulation.bodies.keys().copied().collect::<Vec<_>>();
+        body_ids.sort();
+        writer.u64(body_ids.len() as u64)?;
+        for body_id in body_ids {
+            let body = &simulation.bodies[&body_id];
+            writer.u64(body_id)?;
+            writer.vector(body.pos)?;
+            writer.vector(body.speed)?;
+            writer.f64(body.mass)?;
+            writer.f64(body.radius)?;
+            writer.f64(body.density)?;
+            writer.f64(body.charge)?;
+            writer.u64(body.species as u64)?;
+        }
+
+        let mut accelerations = match &simulation.solver {
+            Solver::BarnesHut(barnes_hut) => barnes_hut
+                .accelerations
+                .iter()
+                .map(|(body_id, acceleration)| (*body_id, *acceleration))
+                .collect(),
+            _ => Vec::new(),
+        };
+        accelerations.sort_by_key(|(body_id, _)| *body_id);
+        writer.u64(accelerations.len() as u64)?;
+        for (body_id, acceleration) in accelerations {
+            writer.u64(body_id)?;
+            writer.f64(acceleration)?;
+        }
+    }
+
+    writer.0.into_inner().map_err(|error| error.into_error())?;
+    fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        let mut reader = Reader(BufReader::new(File::open(path)?));
+
+        if &reader.bytes::<8>()? != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+        let dimensions = reader.u8()?;
+        if dimensions as usize != DIMENSIONS {
+            return Err(SnapshotError::Dimensions(dimensions));
+        }
+
+        let g = reader.f64()?;
+        let dt = reader.f64()?;
+        let theta = reader.f64()?;
+        let tau = reader.f64()?;
+        let integrator = match reader.u8()? {
+            0 => Integrator::SymplecticEuler,
+            1 => Integrator::Leapfrog,
+            tag => return Err(invalid("integrator", tag)),
+        };
+        let collisions = match reader.u8()? {
+            0 => Collisions::Merge,
+            1 => Collisions::None,
+            tag => return Err(invalid("collision model", tag)),
+        };
+
+        let forces = reader.json::<Forces>()?;
+        // The logarithmic law is the gravity of a 2D world
+        if cfg!(feature = "3d") && matches!(forces.law, ForceLaw::Logarithmic) {
+            return Err(SnapshotError::Invalid(
+                "the logarithmic force law in 3D".to_owned(),
+            ));
+        }
+        let units = reader.json::<Units>()?;
+
+        let seed = reader.u64()?;
+        let mut rng = ChaCha12Rng::from_seed(reader.bytes()?);
+        rng.set_stream(reader.u64()?);
+        rng.set_word_pos(reader.u128()?);
+
+        let simulations_n = reader.u32()?;
+        if simulations_n == 0 {
+            return Err(SnapshotError::Invalid("no simulations".to_owned()));
+        }
+        // Not allocated up front, as the counts may be corrupt
+        let mut simulations = Vec::new();
+        for _ in 0..simulations_n {
+            let mut solver = get_solver(reader.bytes()?)?;
+            let time = reader.f64()?;
+            let steps = reader.u64()?;
+            let initial_energy = reader.option_f64()?;
+
+            let frame = match reader.u8()? {
+                0 => None,
+                1 => Some(RotatingFrame {
+                    center: reader.vector()?,
+                    angular_speed: reader.f64()?,
+                    angle: reader.f64()?,
+                }),
+                tag => return Err(invalid("frame", tag)),
+            };
+
+            let bodies_n = reader.u64()?;
+            let mut bodies = HashMap::new();
+            for _ in 0..bodies_n {
+                let body_id = reader.u64()?;
+                if !reserve_body_id(body_id) {
+                    return Err(SnapshotError::Invalid(format!(
+                        "body {} leaves no IDs for new bodies",
+                        body_id
+                    )));
+                }
+
+                let body = Body {
+                    pos: reader.vector()?,
+                    speed: reader.vector()?,
+                    mass: reader.f64()?,
+                    radius: reader.f64()?,
+                    density: reader.f64()?,
+                    charge: reader.f64()?,
+                    species: reader.u64()? as usize,
+                };
+                if body.species > DUST {
+                    return Err(SnapshotError::Invalid(format!(
+                        "body {} is of an unknown species {}",
+                        body_id, body.species
+                    )));
+                }
+                if bodies.insert(body_id, body).is_some() {
+                    return Err(SnapshotError::Invalid(format!(
+                        "body {} is there twice",
+                        body_id
+                    )));
+                }
+            }
+
+            let accelerations_n = reader.u64()?;
+            let mut accelerations = HashMap::new();
+            for _ in 0..accelerations_n {
+                accelerations.insert(reader.u64()?, reader.f64()?);
+            }
+            if let Solver::BarnesHut(barnes_hut) = &mut solver {
+                barnes_hut.accelerations = accelerations;
+            } else if !accelerations.is_empty() {
+                return Err(SnapshotError::Invalid(
+                    "accelerations kept by a solver other than Barnes-Hut".to_owned(),
+                ));
+            }
+
+            let mut simulation = Simulation::new(solver, bodies);
+            simulation.time = time;
+            simulation.steps = steps;
+            simulation.initial_energy = initial_energy;
+            simulation.frame = frame;
+            simulations.push(simulation);
+        }
+
+        Ok(Self {
+            g,
+            dt,
+            theta,
+            tau,
+            integrator,
+            collisions,
+            forces,
+            units,
+            seed,
+            rng,
+            simulations,
+        })
+    }
+
+    /// Sets the global constants and models back to what they were.
+    pub fn apply(&self) {
+        G.set(self.g);
+        DT.set(self.dt);
+        *THETA.write().unwrap() = self.theta;
+        *TAU.write().unwrap() = self.tau;
+        *INTEGRATOR.write().unwrap() = self.integrator;
+        *COLLISIONS.write().unwrap() = self.collisions;
+
+        self.forces.apply();
+        *SCALES.write().unwrap() = self.units.get_scales(self.g);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{body::BodyID, units::UnitSystem};
+    use std::{env, path::PathBuf};
+
+    /// Where the constants end, before the JSON of the forces and of the units.
+    const CONSTANTS_END: usize = 8 + 4 + 1 + 4 * 8 + 2;
+    /// The seed of the initial conditions and the state of the random generator.
+    const RNG_SIZE: usize = 8 + 32 + 8 + 16;
+
+    /// A file of its own, as the tests run side by side.
+    fn get_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("gravity_{}_{}.snapshot", name, std::process::id()))
+    }
+
+    fn get_bodies() -> HashMap<BodyID, Body> {
+        (0..3)
+            .map(|index| {
+                let body = Body {
+                    pos: Vector::from_xy(10.0 * index as f64, -5.0),
+                    speed: Vector::from_xy(0.0, 0.1 * index as f64),
+                    mass: 1.0 + index as f64,
+                    radius: 1.0,
+                    density: 1.0 + index as f64,
+                    charge: -1.0,
+                    species: index % 2,
+                };
+
+                (index as BodyID, body)
+            })
+            .collect()
+    }
+
+    /// A direct simulation fresh from its initial conditions, then a Barnes-Hut one
+    /// some way in, in a rotating frame and with accelerations kept.
+    fn get_simulations() -> Vec<Simulation> {
+        let direct = Simulation::new(Solver::Direct, get_bodies());
+
+        let mut barnes_hut = BarnesHut {
+            traversal: Traversal::DualTree,
+            mac: Mac::Bmax,
+            incremental: true,
+            ..Default::default()
+        };
+        barnes_hut.accelerations = HashMap::from([(0, 1e-3), (2, 5e-4)]);
+        let mut simulation = Simulation::new(Solver::BarnesHut(barnes_hut), get_bodies());
+        simulation.time = 12.5;
+        simulation.steps = 25;
+        simulation.initial_energy = Some(-3.0);
+        simulation.frame = Some(RotatingFrame {
+            center: Vector::from_xy(1.0, 2.0),
+            angular_speed: 1e-3,
+            angle: 0.25,
+        });
+
+        vec![direct, simulation]
+    }
+
+    fn save_simulations(path: &Path) -> Vec<u8> {
+        let units = Units {
+            system: UnitSystem::Astronomical,
+            length: 2.0,
+            mass: 3.0,
+        };
+        let rng = ChaCha12Rng::seed_from_u64(7);
+        save(path, &get_simulations(), &units, 42, &rng).unwrap();
+
+        fs::read(path).unwrap()
+    }
+
+    /// Where the number of simulations is, after the JSON of the forces and of the units.
+    fn get_simulations_n_offset(bytes: &[u8]) -> usize {
+        let mut offset = CONSTANTS_END;
+        for _ in 0..2 {
+            let length = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            offset += 4 + length as usize;
+        }
+
+        offset + RNG_SIZE
+    }
+
+    /// Writes `bytes` over the file at `offset`, then loads it.
+    fn load_corrupted(path: &Path, offset: usize, bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut file = fs::read(path).unwrap();
+        file[offset..offset + bytes.len()].copy_from_slice(bytes);
+        fs::write(path, file).unwrap();
+
+        Snapshot::load(path)
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = get_path("round_trip");
+        save_simulations(&path);
+        let snapshot = Snapshot::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(snapshot.units.system, UnitSystem::Astronomical);
+        assert_eq!((snapshot.units.length, snapshot.units.mass), (2.0, 3.0));
+        assert_eq!(snapshot.seed, 42);
+        assert_eq!(snapshot.rng, ChaCha12Rng::seed_from_u64(7));
+
+        let simulations = get_simulations();
+        assert_eq!(snapshot.simulations.len(), simulations.len());
+        for (loaded, simulation) in snapshot.simulations.iter().zip(&simulations) {
+            assert_eq!(
+                get_solver_tags(&loaded.solver),
+                get_solver_tags(&simulation.solver)
+            );
+            assert_eq!(loaded.bodies, simulation.bodies);
+            assert_eq!(loaded.time, simulation.time);
+            assert_eq!(loaded.steps, simulation.steps);
+            assert_eq!(loaded.initial_energy, simulation.initial_energy);
+            assert_eq!(loaded.frame, simulation.frame);
+            if let (Solver::BarnesHut(loaded), Solver::BarnesHut(barnes_hut)) =
+                (&loaded.solver, &simulation.solver)
+            {
+                assert_eq!(loaded.accelerations, barnes_hut.accelerations);
+            }
+        }
+    }
+
+    /// Huge counts run out of file instead of memory.
+    #[test]
+    fn corrupted_counts() {
+        let path = get_path("corrupted_counts");
+        let bytes = save_simulations(&path);
+
+        let simulations_n_offset = get_simulations_n_offset(&bytes);
+        let error = load_corrupted(&path, simulations_n_offset, &u32::MAX.to_le_bytes());
+        assert!(matches!(error, Err(SnapshotError::Io(_))));
+
+        // Past the solver, the time, the steps, and the energy baseline and frame
+        // the direct simulation has none of. What comes after is then read as bodies,
+        // which fails one way or another
+        fs::write(&path, &bytes).unwrap();
+        let bodies_n_offset = simulations_n_offset + 4 + 4 + 8 + 8 + 1 + 1;
+        let error = load_corrupted(&path, bodies_n_offset, &u64::MAX.to_le_bytes());
+        assert!(error.is_err());
+
+        fs::write(&path, &bytes).unwrap();
+        let error = load_corrupted(&path, CONSTANTS_END, &u32::MAX.to_le_bytes());
+        assert!(matches!(error, Err(SnapshotError::Invalid(_))));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
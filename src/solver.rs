@@ -0,0 +1,281 @@
+use crate::{
+    Camera, MAX_AVERAGE_LENGTH,
+    barnes_hut::{BarnesHut, Traversal},
+    body::{Body, BodyID, COLLISIONS, Collisions, Energy},
+    direct::Direct,
+    drag::{MEDIUM, SPECIES},
+    external_field::{EXTERNAL_FIELDS, get_external_acceleration, get_external_potential},
+    force_law::{FORCE_LAW, ForceLaw},
+    frame::RotatingFrame,
+    grid::Grid,
+    multigrid::MultiGrid,
+    vector::VectorExt,
+};
+use macroquad::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+pub static INTEGRATOR: LazyLock<RwLock<Integrator>> =
+    LazyLock::new(|| RwLock::new(Integrator::SymplecticEuler));
+
+/// How a step interleaves moving the bodies with their speeds (the drift)
+/// and changing the speeds by the forces (the kick).
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    /// A drift over the whole step, then a kick: first order.
+    #[default]
+    SymplecticEuler,
+    /// Drift-kick-drift, with the kick halfway through the step: second order
+    /// for the same number of force evaluations.
+    Leapfrog,
+}
+
+pub enum Solver {
+    Direct,
+    BarnesHut(BarnesHut),
+    MultiGrid,
+    Grid,
+}
+
+impl Solver {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Direct => "Direct",
+            Self::BarnesHut(barnes_hut) => match barnes_hut.traversal {
+                Traversal::Bodies => "Barnes-Hut",
+                Traversal::DualTree => "Barnes-Hut (dual tree)",
+            },
+            Self::MultiGrid => "Multigrid",
+            Self::Grid => "Grid",
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            Self::Direct => Direct::COLOR,
+            Self::BarnesHut(barnes_hut) => match barnes_hut.traversal {
+                Traversal::Bodies => BarnesHut::COLOR,
+                Traversal::DualTree => BarnesHut::DUAL_TREE_COLOR,
+            },
+            Self::MultiGrid => MultiGrid::COLOR,
+            Self::Grid => Grid::COLOR,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        bodies: &mut HashMap<BodyID, Body>,
+        force_law: ForceLaw,
+        camera: &Camera,
+    ) -> Duration {
+        match self {
+            Self::Direct => Direct::handle(bodies, force_law),
+            Self::BarnesHut(barnes_hut) => barnes_hut.handle(bodies, force_law, camera),
+            Self::MultiGrid => MultiGrid::handle(bodies, force_law, camera),
+            Self::Grid => Grid::handle(bodies, force_law, camera),
+        }
+    }
+}
+
+/// One of the simulations running side by side, each with its own copy of the bodies.
+pub struct Simulation {
+    pub solver: Solver,
+    pub bodies: HashMap<BodyID, Body>,
+    pub durations: Vec<f64>,
+    /// The duration per body of the latest step.
+    pub duration: f64,
+    /// The energy after the latest step, if diagnostics are on.
+    pub energy: Option<Energy>,
+    /// The total energy the drift is measured against.
+    pub initial_energy: Option<f64>,
+    /// The simulated time, which time-dependent external fields follow.
+    pub time: f64,
+    pub steps: u64,
+    /// The frame the bodies are simulated in, if not the inertial one.
+    pub frame: Option<RotatingFrame>,
+}
+
+impl Simulation {
+    pub fn new(solver: Solver, bodies: HashMap<BodyID, Body>) -> Self {
+        Self {
+            solver,
+            bodies,
+            durations: Vec::with_capacity(MAX_AVERAGE_LENGTH.get()),
+            duration: 0.0,
+            energy: None,
+            initial_energy: None,
+            time: 0.0,
+            steps: 0,
+            frame: None,
+        }
+    }
+
+    /// Starts over from `bodies`, in the inertial frame.
+    pub fn reset(&mut self, bodies: HashMap<BodyID, Body>) {
+        self.bodies = bodies;
+        self.time = 0.0;
+        self.steps = 0;
+        self.frame = None;
+        self.reset_energy();
+
+        if let Solver::BarnesHut(barnes_hut) = &mut self.solver {
+            barnes_hut.tree = None;
+            barnes_hut.accelerations.clear();
+            barnes_hut.rebuilds_n = 0;
+        }
+    }
+
+    pub fn get_average(&self) -> f64 {
+        self.durations.iter().sum::<f64>() / self.durations.len() as f64
+    }
+
+    pub fn measure_energy(&mut self) {
+        let mut energy = Body::get_energy(&self.bodies);
+
+        let external_fields = EXTERNAL_FIELDS.read().unwrap();
+        energy.potential += self
+            .bodies
+            .values()
+            .map(|body| {
+                let pos = match &self.frame {
+                    Some(frame) => frame.to_inertial_pos(body.pos),
+                    None => body.pos,
+                };
+                body.mass * get_external_potential(&external_fields, pos, self.time)
+            })
+            .sum::<f64>();
+
+        // In a rotating frame, this makes the total the Jacobi integral
+        if let Some(frame) = &self.frame {
+            energy.potential += self
+                .bodies
+                .values()
+                .map(|body| body.mass * frame.get_centrifugal_potential(body.pos))
+                .sum::<f64>();
+        }
+
+        self.initial_energy.get_or_insert(energy.get_total());
+        self.energy = Some(energy);
+    }
+
+    /// The relative change of the total energy since the diagnostics were reset.
+    pub fn get_energy_drift(&self) -> Option<f64> {
+        Some((self.energy?.get_total() - self.initial_energy?) / self.initial_energy?.abs())
+    }
+
+    pub fn reset_energy(&mut self) {
+        self.energy = None;
+        self.initial_energy = None;
+    }
+
+    /// Moves the bodies into `frame`, or into the inertial frame if `None`.
+    pub fn set_frame(&mut self, frame: Option<RotatingFrame>) {
+        for body in self.bodies.values_mut() {
+            if let Some(old) = &self.frame {
+                *body = old.to_inertial(body);
+            }
+            if let Some(new) = &frame {
+                *body = new.to_rotating(body);
+            }
+        }
+
+        self.frame = frame;
+        self.reset_energy();
+    }
+
+    /// Accumulates the forces beyond the solver's: external fields, drag
+    /// and, in a rotating frame, the fictitious forces.
+    pub fn apply_forces(&mut self, dt: f64) {
+        let external_fields = EXTERNAL_FIELDS.read().unwrap();
+        let medium = *MEDIUM.read().unwrap();
+        let species = SPECIES.read().unwrap();
+
+        if external_fields.is_empty() && medium.is_none() && self.frame.is_none() {
+            return;
+        }
+
+        for body in self.bodies.values_mut() {
+            // External fields and the medium are given in the inertial frame
+            let inertial = match &self.frame {
+                Some(frame) => frame.to_inertial(body),
+                None => *body,
+            };
+
+            let mut acceleration =
+                get_external_acceleration(&external_fields, inertial.pos, self.time);
+
+            if let Some(medium) = &medium
+                && let Some(drag) = species[body.species].drag
+            {
+                acceleration += medium.get_drag_acceleration(&inertial, drag, dt);
+            }
+
+            if let Some(frame) = &self.frame {
+                acceleration = acceleration.rotate_xy(-frame.angle)
+                    + frame.get_fictitious_acceleration(body.pos, body.speed, dt);
+            }
+
+            body.speed += dt * acceleration;
+        }
+    }
+
+    /// Moves the bodies with their speeds, merging them on collision if they do.
+    fn drift(&mut self, dt: f64, collisions: Collisions) {
+        match collisions {
+            Collisions::Merge => Body::update_bodies(dt, &mut self.bodies),
+            Collisions::None => Body::drift(dt, &mut self.bodies),
+        }
+        self.time += dt;
+        if let Some(frame) = &mut self.frame {
+            frame.angle += frame.angular_speed * dt;
+        }
+    }
+
+    pub fn step(&mut self, dt: f64, always_use_direct: bool, camera: &Camera) {
+        let integrator = *INTEGRATOR.read().unwrap();
+        let collisions = *COLLISIONS.read().unwrap();
+
+        self.drift(
+            match integrator {
+                Integrator::SymplecticEuler => dt,
+                Integrator::Leapfrog => dt / 2.0,
+            },
+            collisions,
+        );
+        // The momentum isn't conserved in a rotating frame, nor under external
+        // fields or drag, whose net force would be cancelled
+        if !matches!(self.solver, Solver::Direct)
+            && self.frame.is_none()
+            && EXTERNAL_FIELDS.read().unwrap().is_empty()
+            && MEDIUM.read().unwrap().is_none()
+        {
+            Body::adjust_momentum(&mut self.bodies);
+        }
+
+        let force_law = *FORCE_LAW.read().unwrap();
+        self.duration = if always_use_direct {
+            Direct::handle(&mut self.bodies, force_law)
+        } else {
+            self.solver.handle(&mut self.bodies, force_law, camera)
+        }
+        .as_nanos() as f64
+            / self.bodies.len() as f64;
+
+        self.apply_forces(dt);
+
+        if integrator == Integrator::Leapfrog {
+            self.drift(dt / 2.0, collisions);
+        }
+        self.steps += 1;
+
+        if self.durations.len() == MAX_AVERAGE_LENGTH.get() {
+            self.durations.clear();
+        }
+        self.durations.push(self.duration);
+    }
+}
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{LazyLock, RwLock};
+
+/// The gravitational constant in m^3 kg^-1 s^-2 (CODATA 2018).
+const SI_G: f64 = 6.674_30e-11;
+/// In metres (IAU 2012).
+const ASTRONOMICAL_UNIT: f64 = 1.495_978_707e11;
+/// In seconds.
+const JULIAN_YEAR: f64 = 365.25 * 86_400.0;
+/// In kilograms, from the heliocentric gravitational constant of the IAU.
+const SOLAR_MASS: f64 = 1.327_124_400_41e20 / SI_G;
+
+/// The scales of the scenario being run, which its outputs are converted back with.
+pub static SCALES: LazyLock<RwLock<Scales>> = LazyLock::new(|| RwLock::new(Scales::IDENTITY));
+
+/// The units the numbers of a scenario are in. The simulation itself runs in its
+/// own units, which its softening, force laws and presets are tuned for.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitSystem {
+    /// Those of the simulation, in which the presets fill the world.
+    #[default]
+    Simulation,
+    /// Metres, seconds and kilograms.
+    Si,
+    /// Astronomical units, Julian years and solar masses.
+    Astronomical,
+    /// Units in which G is 1.
+    NBody,
+}
+
+/// How a scenario's units map onto the simulation's: a simulation unit of length is
+/// `length` units of `system`, one of mass is `mass` of them, and one of time
+/// whatever makes G `constants.g` in the simulation.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Units {
+    pub system: UnitSystem,
+    pub length: f64,
+    pub mass: f64,
+}
+
+/// The sizes of the simulation's units of length, time and mass in a scenario's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scales {
+    pub length: f64,
+    pub time: f64,
+    pub mass: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Quantity {
+    Length,
+    Time,
+    Mass,
+    Speed,
+    Acceleration,
+    Density,
+    Energy,
+    /// Rates and angular speeds.
+    Frequency,
+    SquaredFrequency,
+    InverseLength,
+}
+
+impl UnitSystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Simulation => "simulation units",
+            Self::Si => "SI",
+            Self::Astronomical => "AU, yr, M☉",
+            Self::NBody => "N-body units",
+        }
+    }
+
+    /// G in these units, if they are physical ones.
+    fn get_g(self) -> Option<f64> {
+        match self {
+            Self::Simulation => None,
+            Self::Si => Some(SI_G),
+            Self::Astronomical => {
+                Some(SI_G * SOLAR_MASS * JULIAN_YEAR.powi(2) / ASTRONOMICAL_UNIT.powi(3))
+            }
+            Self::NBody => Some(1.0),
+        }
+    }
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Self {
+            system: UnitSystem::Simulation,
+            length: 1.0,
+            mass: 1.0,
+        }
+    }
+}
+
+impl Units {
+    /// The scales under which G is `g` in the simulation.
+    pub fn get_scales(&self, g: f64) -> Scales {
+        let system_g = self.system.get_g().unwrap_or(g);
+
+        Scales {
+            length: self.length,
+            time: (g * self.length.powi(3) / (system_g * self.mass)).sqrt(),
+            mass: self.mass,
+        }
+    }
+}
+
+impl Quantity {
+    /// The powers of length, time and mass it is made of.
+    fn get_dimensions(self) -> [i32; 3] {
+        match self {
+            Self::Length => [1, 0, 0],
+            Self::Time => [0, 1, 0],
+            Self::Mass => [0, 0, 1],
+            Self::Speed => [1, -1, 0],
+            Self::Acceleration => [1, -2, 0],
+            Self::Density => [-3, 0, 1],
+            Self::Energy => [2, -2, 1],
+            Self::Frequency => [0, -1, 0],
+            Self::SquaredFrequency => [0, -2, 0],
+            Self::InverseLength => [-1, 0, 0],
+        }
+    }
+}
+
+impl Scales {
+    pub const IDENTITY: Self = Self {
+        length: 1.0,
+        time: 1.0,
+        mass: 1.0,
+    };
+
+    /// The size of the simulation's unit of `quantity` in the scenario's units.
+    fn get(&self, quantity: Quantity) -> f64 {
+        let [length, time, mass] = quantity.get_dimensions();
+
+        self.length.powi(length) * self.time.powi(time) * self.mass.powi(mass)
+    }
+
+    /// `value` of `quantity` in the scenario's units, in the simulation's.
+    pub fn in_simulation(&self, quantity: Quantity, value: f64) -> f64 {
+        value / self.get(quantity)
+    }
+
+    /// `value` of `quantity` in the simulation's units, in the scenario's.
+    pub fn in_scenario(&self, quantity: Quantity, value: f64) -> f64 {
+        value * self.get(quantity)
+    }
+}
@@ -0,0 +1,157 @@
+use ::rand::Rng;
+#[cfg(feature = "3d")]
+use macroquad::prelude::*;
+#[cfg(not(feature = "3d"))]
+use num_complex::{Complex, ComplexFloat};
+use std::f64::consts::PI;
+
+/// Positions and speeds: complex numbers in 2D, `DVec3` in 3D (the `3d` feature).
+/// Everything else is written against `VectorExt`, so the same bodies,
+/// integrator and solvers work in both.
+#[cfg(not(feature = "3d"))]
+pub type Vector = Complex<f64>;
+#[cfg(feature = "3d")]
+pub type Vector = DVec3;
+
+#[cfg(not(feature = "3d"))]
+pub const DIMENSIONS: usize = 2;
+#[cfg(feature = "3d")]
+pub const DIMENSIONS: usize = 3;
+
+/// The number of children of a tree node, the corners of a box, etc.
+pub const ORTHANTS_N: usize = 1 << DIMENSIONS;
+
+pub trait VectorExt: Sized {
+    fn from_components(components: [f64; DIMENSIONS]) -> Self;
+    fn components(&self) -> [f64; DIMENSIONS];
+    // `DVec3` has inherent `length` and `dot` of its own, which take precedence
+    #[cfg_attr(feature = "3d", allow(dead_code))]
+    fn length(&self) -> f64;
+    #[cfg_attr(feature = "3d", allow(dead_code))]
+    fn dot(self, other: Self) -> f64;
+    fn x(&self) -> f64;
+    fn y(&self) -> f64;
+
+    fn splat(value: f64) -> Self {
+        Self::from_components([value; DIMENSIONS])
+    }
+
+    /// In the xy plane, the other components being zero.
+    fn from_xy(x: f64, y: f64) -> Self {
+        Self::splat(0.0).with_xy(x, y)
+    }
+
+    /// With the components in the xy plane replaced, the others being kept.
+    fn with_xy(&self, x: f64, y: f64) -> Self {
+        let mut components = self.components();
+        components[0] = x;
+        components[1] = y;
+
+        Self::from_components(components)
+    }
+
+    /// Rotated by `angle` in the xy plane, about the origin.
+    fn rotate_xy(&self, angle: f64) -> Self {
+        let (x, y) = (self.x(), self.y());
+        self.with_xy(
+            x * angle.cos() - y * angle.sin(),
+            x * angle.sin() + y * angle.cos(),
+        )
+    }
+
+    /// Uniformly distributed over the unit circle or sphere.
+    fn random_direction(rng: &mut impl Rng) -> Self;
+
+    /// The corner `orthant` of the box at `corner`, bit `k` of `orthant`
+    /// telling whether to go the component `k` of `size` along the axis `k`.
+    fn get_orthant(&self, orthant: usize, size: Self) -> Self {
+        let mut components = self.components();
+        for (k, (component, size)) in components.iter_mut().zip(size.components()).enumerate() {
+            if orthant >> k & 1 == 1 {
+                *component += size;
+            }
+        }
+
+        Self::from_components(components)
+    }
+}
+
+#[cfg(not(feature = "3d"))]
+impl VectorExt for Vector {
+    fn from_components(components: [f64; DIMENSIONS]) -> Self {
+        Complex::new(components[0], components[1])
+    }
+
+    fn components(&self) -> [f64; DIMENSIONS] {
+        [self.re(), self.im()]
+    }
+
+    fn length(&self) -> f64 {
+        self.abs()
+    }
+
+    fn dot(self, other: Self) -> f64 {
+        self.re() * other.re() + self.im() * other.im()
+    }
+
+    fn x(&self) -> f64 {
+        self.re()
+    }
+
+    fn y(&self) -> f64 {
+        self.im()
+    }
+
+    fn random_direction(rng: &mut impl Rng) -> Self {
+        Complex::from_polar(1.0, rng.random_range(0.0..2.0 * PI))
+    }
+}
+
+#[cfg(feature = "3d")]
+impl VectorExt for Vector {
+    fn from_components(components: [f64; DIMENSIONS]) -> Self {
+        DVec3::from_array(components)
+    }
+
+    fn components(&self) -> [f64; DIMENSIONS] {
+        self.to_array()
+    }
+
+    fn length(&self) -> f64 {
+        DVec3::length(*self)
+    }
+
+    fn dot(self, other: Self) -> f64 {
+        DVec3::dot(self, other)
+    }
+
+    fn x(&self) -> f64 {
+        self.x
+    }
+
+    fn y(&self) -> f64 {
+        self.y
+    }
+
+    fn random_direction(rng: &mut impl Rng) -> Self {
+        let z: f64 = rng.random_range(-1.0..1.0);
+        let angle = rng.random_range(0.0..2.0 * PI);
+        let r = (1.0 - z * z).sqrt();
+
+        DVec3::new(r * angle.cos(), r * angle.sin(), z)
+    }
+}
+
+/// A vector in a scenario, as the list of its components, for `#[serde(with)]`.
+pub mod components {
+    use super::{DIMENSIONS, Vector, VectorExt};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(vector: &Vector, serializer: S) -> Result<S::Ok, S::Error> {
+        vector.components().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vector, D::Error> {
+        <[f64; DIMENSIONS]>::deserialize(deserializer).map(Vector::from_components)
+    }
+}